@@ -1,9 +1,11 @@
+use crate::dirs;
 use crate::event::{
-    Action, AppAction, CommandAction, Event, EventProcessor, InputAction, Key, NetEvent,
-    NetEventKind,
+    Action, AppAction, CommandAction, Event, EventProcessor, InputAction, Key, MatrixMembership,
+    MatrixPresence, MessageKind, NetEvent, NetEventKind, Presence, RoomAction, RoomPublish,
+    STATE_DIRECT,
 };
 use crate::gui_dbg;
-use crate::input::{command::Command, Input};
+use crate::input::{command::Command, slash, Input};
 use crate::room;
 use crate::sequence_number::SequenceNumber;
 use crate::widget::Height;
@@ -82,6 +84,10 @@ pub struct App {
     input: Input,
     command: Command,
     focus: Focus,
+    show_members: bool,
+
+    // Last known presence of every user, by user ID
+    presence: HashMap<String, Presence>,
 
     receiver: mpsc::Receiver<Event>,
     pub sender: mpsc::Sender<Event>,
@@ -101,6 +107,8 @@ impl App {
             input: Input::default(),
             command: Command::default(),
             focus: Focus::None,
+            show_members: false,
+            presence: HashMap::new(),
             receiver,
             sender,
             room_sn: Arc::new(Mutex::new(SequenceNumber::default())),
@@ -131,6 +139,7 @@ impl App {
                     room::ui::Conf {
                         alias: name,
                         meta_width: 16,
+                        markdown: true,
                     },
                 ),
                 net_sender: requester,
@@ -159,29 +168,125 @@ impl App {
     }
 
     async fn room_send(&mut self, action: room::net::ActionKind) {
-        let action = room::net::Action {
-            room: self.current_room,
-            action,
-        };
-        self.mut_room()
-            .net_sender
-            .send(action)
+        self.room_send_to(self.rooms_id[self.current_room], action)
             .await
-            .expect("TODO Implement room exiting");
+    }
+
+    async fn room_send_to(&mut self, id: room::Id, action: room::net::ActionKind) {
+        let action = room::net::Action { room: id, action };
+        let room = match self.get_mut_room(id) {
+            Some(room) => room,
+            None => {
+                gui_dbg!("Action sent to dead room {}: {:?}", id, action);
+                return;
+            }
+        };
+        if room.net_sender.send(action).await.is_err() {
+            let error = NetEventKind::Error("The room is not connected anymore".to_string());
+            room.ui.process_event(error.to_current_event(id, None));
+        }
+    }
+
+    /// Show a local error in the current room.
+    fn room_error(&mut self, error: String) {
+        let id = self.rooms_id[self.current_room];
+        let error = NetEventKind::Error(error).to_current_event(id, None);
+        self.mut_room().ui.process_event(error);
+    }
+
+    fn room_info(&mut self, info: String) {
+        let id = self.rooms_id[self.current_room];
+        let info = NetEventKind::Info(info).to_current_event(id, None);
+        self.mut_room().ui.process_event(info);
+    }
+
+    /// Edit of the latest message of the local user with a `s/pattern/replacement/` command.
+    fn substitute(&self, substitution: slash::Substitution) -> Result<room::net::Publish, String> {
+        let message = match self.room().ui.last_own_message() {
+            Some(message) => message,
+            None => return Err("No message of yours to edit in this room".to_string()),
+        };
+        let body = match substitution.apply(&message.content) {
+            Some(body) => body,
+            None => return Err("Pattern not found in your last message".to_string()),
+        };
+        let msgtype = match message.kind {
+            MessageKind::Emote => room::net::MessageType::Emote,
+            MessageKind::Notice => room::net::MessageType::Notice,
+            _ => room::net::MessageType::Text,
+        };
+        let mut publish = slash::message(msgtype, &body, self.room().ui.conf.markdown);
+        publish.relation = message.id.clone().map(room::net::Relation::Replace);
+        Ok(publish)
+    }
+
+    async fn publish(&mut self, msg: String) {
+        if self.input.stop_typing().is_some() {
+            self.room_send(room::net::ActionKind::Typing(false)).await;
+        }
+        let publish = match slash::Substitution::parse(&msg) {
+            Some(substitution) => self.substitute(substitution),
+            None => slash::parse(&msg, self.room().ui.conf.markdown),
+        };
+        match publish {
+            Ok(mut publish) => {
+                let ui = &mut self.mut_room().ui;
+                if let Some(txn_id) = ui.editing_unsent.take() {
+                    self.context.status.clear();
+                    let action = room::net::ActionKind::EditQueued { txn_id, publish };
+                    return self.room_send(action).await;
+                } else if let Some(id) = ui.editing.take() {
+                    publish.relation = Some(room::net::Relation::Replace(id));
+                    self.context.status.clear();
+                } else if let Some(reply) = ui.reply.take() {
+                    publish.relation = Some(room::net::Relation::InReplyTo(reply));
+                    self.context.status.clear();
+                }
+                self.room_send(room::net::ActionKind::Publish(publish))
+                    .await
+            }
+            Err(e) => self.room_error(e),
+        }
+    }
+
+    /// Mark the messages of the displayed room as read.
+    fn view_current_room(&mut self) -> Vec<Action> {
+        let current = self.rooms_id[self.current_room];
+        for (id, room) in self.rooms.iter_mut() {
+            room.ui.set_viewed(*id == current);
+        }
+        self.mut_room().ui.mark_read()
+    }
+
+    /// Drop a room from the UI, shutting down its connection to the network side.
+    fn remove_room(&mut self, id: room::Id) {
+        if self.rooms.remove(&id).is_none() {
+            return;
+        }
+        if let Some(index) = self.rooms_id.iter().position(|r| *r == id) {
+            self.rooms_id.remove(index);
+            if self.current_room > index {
+                self.current_room -= 1;
+            } else if self.current_room == index {
+                // Show the room that took its place, or the previous one if it was the last
+                self.current_room = index.min(self.rooms_id.len().saturating_sub(1));
+                if let Focus::Room = self.focus {
+                    self.focus = Focus::None;
+                }
+            }
+        }
     }
 
     async fn execute_action(&mut self, ctx_mod: Action) -> Vec<LoopAction> {
         let mut ret = vec![];
         match ctx_mod {
             Action::Input(act) => match act {
-                InputAction::Message(msg) => {
-                    self.room_send(room::net::ActionKind::Publish(msg)).await
-                }
+                InputAction::Message(msg) => self.publish(msg).await,
             },
             Action::Command(act) => match act {
                 CommandAction::Save => {
                     let msg = self.input.text_widget.text.consume();
-                    self.room_send(room::net::ActionKind::Publish(msg)).await;
+                    self.publish(msg).await;
                 }
                 CommandAction::Quit => ret.push(LoopAction::Quit),
                 CommandAction::NewRoom(r) => {
@@ -191,11 +296,89 @@ impl App {
                 CommandAction::Disconnect => {
                     self.room_send(room::net::ActionKind::Disconnect).await
                 }
+                CommandAction::Close { forget } => {
+                    self.room_send(room::net::ActionKind::Close { forget })
+                        .await
+                }
+                CommandAction::Markdown(markdown) => {
+                    self.mut_room().ui.conf.markdown = markdown;
+                    self.room_info(format!(
+                        "Messages are now sent as {}",
+                        if markdown { "markdown" } else { "plain text" }
+                    ));
+                }
+                CommandAction::Redact(reason) => match self.room().ui.selected_message_id() {
+                    Ok(id) => {
+                        self.room_send(room::net::ActionKind::Redact { id, reason })
+                            .await
+                    }
+                    Err(e) => self.context.status = e,
+                },
+                CommandAction::React(key) => match self.room().ui.toggle_reaction(key) {
+                    Ok(action) => self.room_send(action).await,
+                    Err(e) => self.context.status = e,
+                },
+                CommandAction::Upload(path) => {
+                    let path = dirs::expand_home(&path);
+                    self.room_send(room::net::ActionKind::Upload(path)).await
+                }
+                CommandAction::Download { open } => match self.room().ui.selected_attachment() {
+                    Ok((url, name)) => {
+                        self.room_send(room::net::ActionKind::Download { url, name, open })
+                            .await
+                    }
+                    Err(e) => self.context.status = e,
+                },
+                CommandAction::Retry => match self.room().ui.failed_message() {
+                    Ok(txn_id) => self.room_send(room::net::ActionKind::Retry(txn_id)).await,
+                    Err(e) => self.context.status = e,
+                },
+                CommandAction::Cancel => match self.room().ui.unsent_message() {
+                    Ok(txn_id) => {
+                        self.mut_room().ui.remove_echo(&txn_id);
+                        self.room_send(room::net::ActionKind::Cancel(txn_id)).await
+                    }
+                    Err(e) => self.context.status = e,
+                },
+                CommandAction::AcceptInvite | CommandAction::DeclineInvite => {
+                    if self.room().ui.invite.is_none() {
+                        self.room_error("No pending invitation".to_string());
+                    } else if let CommandAction::AcceptInvite = act {
+                        self.room_send(room::net::ActionKind::Connect).await
+                    } else {
+                        self.room_send(room::net::ActionKind::Disconnect).await
+                    }
+                }
+            },
+            Action::Room(act) => match act {
+                RoomAction::Publish(RoomPublish { id, msg }) => {
+                    let publish = room::net::Publish::new(room::net::MessageType::Text, msg);
+                    self.room_send_to(id, room::net::ActionKind::Publish(publish))
+                        .await
+                }
+                RoomAction::LoadHistory => self.room_send(room::net::ActionKind::LoadHistory).await,
+                RoomAction::LoadMembers => self.room_send(room::net::ActionKind::LoadMembers).await,
+                RoomAction::LoadEvent { room, id } => {
+                    self.room_send_to(room, room::net::ActionKind::LoadEvent(id))
+                        .await
+                }
+                RoomAction::Typing(typing) => {
+                    self.room_send(room::net::ActionKind::Typing(typing)).await
+                }
+                RoomAction::MarkRead { room, id } => {
+                    self.room_send_to(room, room::net::ActionKind::MarkRead(id))
+                        .await
+                }
             },
-            Action::Room(_) => todo!(),
             Action::App(act) => match act {
                 AppAction::CopyBufferSet(buf) => self.context.copy_buffer = buf,
                 AppAction::StatusSet(status) => self.context.status = status,
+                AppAction::InputSet(text) => self.input.set_text(&text),
+                AppAction::FocusInput => {
+                    self.focus = Focus::Input;
+                    self.input.set_insert_mode();
+                    self.input.receive_focus();
+                }
             },
             Action::FocusLoss => self.focus = Focus::None,
         }
@@ -212,9 +395,22 @@ impl App {
         match event {
             ev @ NetEventKind::Connected
             | ev @ NetEventKind::Disconnected
-            | ev @ NetEventKind::Invite
+            | ev @ NetEventKind::Invite(_)
             | ev @ NetEventKind::Message(_)
-            | ev @ NetEventKind::Presence(_)
+            | ev @ NetEventKind::Referenced(_)
+            | ev @ NetEventKind::HistoryLoaded
+            | ev @ NetEventKind::Edit(_)
+            | ev @ NetEventKind::Redaction(_)
+            | ev @ NetEventKind::Reaction(_)
+            | ev @ NetEventKind::Receipt(_)
+            | ev @ NetEventKind::FullyRead(_)
+            | ev @ NetEventKind::Unread(_)
+            | ev @ NetEventKind::Typing(_)
+            | ev @ NetEventKind::Delivery(_)
+            | ev @ NetEventKind::Info(_)
+            | ev @ NetEventKind::Rename(_)
+            | ev @ NetEventKind::State(_)
+            | ev @ NetEventKind::Member(_)
             | ev @ NetEventKind::Error(_)
             | ev @ NetEventKind::Unknown(_) => match self.get_mut_room(room) {
                 Some(r) => r.ui.process_event(ev.to_event(room, date, source)),
@@ -223,6 +419,19 @@ impl App {
                     vec![]
                 }
             },
+            // Presence is shown in the member and room lists, not in the timelines
+            NetEventKind::Progress(status) => {
+                self.context.status = status;
+                vec![]
+            }
+            NetEventKind::Presence(p) => {
+                self.presence.insert(p.id.clone(), p);
+                vec![]
+            }
+            NetEventKind::Closed => {
+                self.remove_room(room);
+                vec![]
+            }
             NetEventKind::NewRoom(r) => {
                 self.add_room(r.id.unwrap(), r.alias, r.requester);
                 vec![]
@@ -272,6 +481,14 @@ impl App {
                         self.focus = Focus::RoomList;
                         vec![]
                     }
+                    'm' => {
+                        self.show_members = !self.show_members;
+                        if self.show_members {
+                            vec![Action::Room(RoomAction::LoadMembers)]
+                        } else {
+                            vec![]
+                        }
+                    }
                     ':' => {
                         self.command.receive_focus();
                         self.focus = Focus::Command;
@@ -286,6 +503,7 @@ impl App {
     }
 
     fn process_room_list_event(&mut self, event: Event) -> Vec<Action> {
+        let previous_room = self.current_room;
         // TODO The ergonomy of these shortcuts is very debatable
         match event {
             Event::Key(k) => match k {
@@ -307,7 +525,61 @@ impl App {
             _ => (),
         }
         self.focus = Focus::None;
-        vec![]
+        if self.show_members && self.current_room != previous_room {
+            vec![Action::Room(RoomAction::LoadMembers)]
+        } else {
+            vec![]
+        }
+    }
+
+    /// Presence indicator of a user, and its color.
+    fn presence_indicator(&self, user_id: &str) -> (&'static str, Color) {
+        match self.presence.get(user_id).map(|p| p.presence) {
+            Some(MatrixPresence::Online) => ("● ", Color::Green),
+            Some(MatrixPresence::Unavailable) => ("◐ ", Color::Yellow),
+            _ => ("○ ", Color::DarkGray),
+        }
+    }
+
+    /// Name of a room in the room list, direct chats showing the presence of their user.
+    fn room_label(&self, id: room::Id) -> String {
+        let ui = &self.rooms[&id].ui;
+        match ui.state.get(STATE_DIRECT) {
+            Some(user_id) => [self.presence_indicator(user_id).0, &ui.list_label()].concat(),
+            None => ui.list_label(),
+        }
+    }
+
+    fn build_member_list(&self) -> Vec<Text<'_>> {
+        let mut list = vec![];
+        for member in self.room().ui.sorted_members().into_iter() {
+            let (indicator, color) = self.presence_indicator(&member.source.id);
+            let rank = if member.power_level >= 100 {
+                "@"
+            } else if member.power_level >= 50 {
+                "+"
+            } else {
+                ""
+            };
+            let style = match member.membership {
+                MatrixMembership::Invite => Style::default().modifier(Modifier::ITALIC),
+                _ => Style::default(),
+            };
+            list.push(Text::styled(indicator, Style::default().fg(color)));
+            list.push(Text::styled([rank, &member.source.name].concat(), style));
+            let status_msg = self
+                .presence
+                .get(&member.source.id)
+                .and_then(|p| p.status_msg.as_ref());
+            if let Some(status_msg) = status_msg {
+                list.push(Text::styled(
+                    [" (", status_msg, ")"].concat(),
+                    Style::default().fg(Color::DarkGray),
+                ));
+            }
+            list.push(Text::raw("\n"));
+        }
+        list
     }
 
     pub async fn run(&mut self) -> Result<(), Error> {
@@ -361,16 +633,21 @@ impl App {
                     )
                     .split(f.size());
 
+                let content_constraints = if self.show_members {
+                    vec![Constraint::Percentage(20), Constraint::Percentage(60), Constraint::Percentage(20)]
+                } else {
+                    vec![Constraint::Percentage(20), Constraint::Percentage(80)]
+                };
                 let content_layout = Layout::default()
                     .direction(Direction::Horizontal)
                     .margin(1)
-                    .constraints([Constraint::Percentage(20), Constraint::Percentage(80)].as_ref())
+                    .constraints(content_constraints.as_slice())
                     .split(main_layout[0]);
 
                 let room_list: Vec<_> = self
                     .rooms_id
                     .iter()
-                    .map(|id| self.rooms.get(id).unwrap().ui.conf.alias.clone()).collect();
+                    .map(|id| self.room_label(*id)).collect();
 
                 // TODO OPTIM: Redraw only widget that have changed
                 gui_dbg!("================================================================================");
@@ -392,6 +669,16 @@ impl App {
                 let room_space = block.inner(content_layout[1]);
                 self.mut_room().ui.render(&mut f, room_space);
 
+                if self.show_members {
+                    gui_dbg!("================================================================================");
+                    gui_dbg!("Rendering member list");
+                    gui_dbg!("================================================================================");
+                    let members = self.build_member_list();
+                    Paragraph::new(members.iter())
+                        .block(Block::default().title("Members").borders(Borders::ALL))
+                        .render(&mut f, content_layout[2]);
+                }
+
                 gui_dbg!("================================================================================");
                 gui_dbg!("Rendering input");
                 gui_dbg!("================================================================================");
@@ -443,6 +730,10 @@ impl App {
                 }
             }
 
+            for action in self.view_current_room().into_iter() {
+                actions.push(action);
+            }
+
             // Process the resulting actions
             let mut loop_actions = vec![];
             for action in actions.into_iter() {
@@ -465,9 +756,16 @@ impl App {
     }
 
     fn build_status_line(&self) -> Vec<Text> {
-        vec![Text::raw(
+        let mut line = vec![Text::raw(
             [self.focus.to_string().as_str(), " | ", &self.context.status].concat(),
-        )]
+        )];
+        if let Some(typing) = self.room().ui.typing_line() {
+            line.push(Text::styled(
+                [" | ", &typing].concat(),
+                Style::default().fg(Color::DarkGray),
+            ));
+        }
+        line
     }
 }
 
@@ -0,0 +1,46 @@
+use std::path::PathBuf;
+
+const APP_NAME: &str = "rust_matrix_client";
+
+// ==============================================================================================
+// User directories
+// ==============================================================================================
+fn home_dir() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(PathBuf::from)
+}
+
+/// Path typed by the user, with a leading `~` standing for the home directory.
+pub fn expand_home(path: &str) -> PathBuf {
+    match (path.strip_prefix('~'), home_dir()) {
+        (Some(rest), Some(home)) if rest.is_empty() || rest.starts_with('/') => {
+            home.join(rest.trim_start_matches('/'))
+        }
+        _ => PathBuf::from(path),
+    }
+}
+
+fn xdg_dir(var: &str, fallback: &str) -> Option<PathBuf> {
+    let base = match std::env::var_os(var) {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => home_dir()?.join(fallback),
+    };
+    Some(base.join(APP_NAME))
+}
+
+/// Directory holding the persistent client data (sessions, ...).
+pub fn data_dir() -> Option<PathBuf> {
+    xdg_dir("XDG_DATA_HOME", ".local/share")
+}
+
+/// Directory holding the data that can be fetched again (media, ...).
+pub fn cache_dir() -> Option<PathBuf> {
+    xdg_dir("XDG_CACHE_HOME", ".cache")
+}
+
+/// Directory the user keeps the downloaded files in.
+pub fn download_dir() -> Option<PathBuf> {
+    match std::env::var_os("XDG_DOWNLOAD_DIR") {
+        Some(dir) if !dir.is_empty() => Some(PathBuf::from(dir)),
+        _ => Some(home_dir()?.join("Downloads")),
+    }
+}
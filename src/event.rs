@@ -3,12 +3,14 @@ use crate::room;
 use chrono::offset::Utc;
 pub use ruma_events::collections::only::Event as MatrixEvent;
 pub use ruma_events::presence::PresenceState as MatrixPresence;
+pub use ruma_events::room::member::MembershipState as MatrixMembership;
 use std::fmt;
 pub use termion::event::{Key, MouseEvent};
 use tokio::sync::mpsc;
 
+// Dates are in ms since the epoch, like Matrix timestamps
 fn now() -> usize {
-    Utc::now().timestamp() as usize
+    Utc::now().timestamp_millis() as usize
 }
 
 // ==============================================================================================
@@ -21,9 +23,148 @@ pub enum Event {
     Net(NetEvent),
 }
 
+/// Author of an event.
+#[derive(Debug, Clone)]
+pub struct Source {
+    pub id: String,
+    pub name: String,
+}
+
+impl Source {
+    pub fn new(id: &str, name: &str) -> Self {
+        Self {
+            id: id.to_string(),
+            name: name.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AttachmentKind {
+    Image,
+    File,
+    Audio,
+    Video,
+}
+
+/// File shared in a message.
+#[derive(Debug, Clone)]
+pub struct Attachment {
+    pub kind: AttachmentKind,
+    pub name: String,
+    pub mimetype: Option<String>,
+    pub size: Option<u64>,
+    // Matrix content URI (mxc://)
+    pub url: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub enum MessageKind {
+    Text,
+    // Action performed by the sender, as in IRC's /me
+    Emote,
+    // Automated message, not to be answered
+    Notice,
+    Attachment(Attachment),
+    Location(String),
+    // Deleted message, with the reason of the deletion
+    Redacted(Option<String>),
+}
+
+/// Delivery state of a message we sent, until it comes back from the server.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Delivery {
+    // Waiting for the connection to the server
+    Queued,
+    Pending,
+    // The server acknowledged it
+    Sent,
+    Failed,
+}
+
 #[derive(Debug, Clone)]
 pub struct Message {
+    pub kind: MessageKind,
     pub content: String,
+    // HTML version of the content, not sanitised yet
+    pub formatted: Option<String>,
+    // Network identifier of the message
+    pub id: Option<String>,
+    // Identifier of the message this one replies to
+    pub in_reply_to: Option<String>,
+    // Sent by the local user
+    pub own: bool,
+    pub edited: bool,
+    // Transaction we sent the message with, if known
+    pub txn_id: Option<String>,
+    // Set on the local echo of a message we sent
+    pub delivery: Option<Delivery>,
+}
+
+impl Message {
+    pub fn new(kind: MessageKind, content: String) -> Self {
+        Self {
+            kind,
+            content,
+            formatted: None,
+            id: None,
+            in_reply_to: None,
+            own: false,
+            edited: false,
+            txn_id: None,
+            delivery: None,
+        }
+    }
+
+    pub fn text(content: String) -> Self {
+        Self::new(MessageKind::Text, content)
+    }
+}
+
+/// Human readable size of a file.
+fn format_size(size: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if size < 1024 {
+        return format!("{} B", size);
+    }
+    let mut size = size as f64 / 1024.;
+    let mut unit = 0;
+    while size >= 1024. && unit < UNITS.len() - 1 {
+        size /= 1024.;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+impl fmt::Display for Attachment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self.kind {
+            AttachmentKind::Image => "image",
+            AttachmentKind::File => "file",
+            AttachmentKind::Audio => "audio",
+            AttachmentKind::Video => "video",
+        };
+        write!(f, "[{}] {}", kind, self.name)?;
+        if let Some(size) = self.size {
+            write!(f, " ({})", format_size(size))?;
+        }
+        match self.url.as_ref() {
+            Some(url) => write!(f, " {}", url),
+            None => write!(f, " (no URL)"),
+        }
+    }
+}
+
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            MessageKind::Attachment(a) => write!(f, "{}", a),
+            MessageKind::Location(geo_uri) => write!(f, "{} ({})", self.content, geo_uri),
+            MessageKind::Redacted(Some(reason)) => write!(f, "Message deleted: {}", reason),
+            MessageKind::Redacted(None) => write!(f, "Message deleted"),
+            _ => write!(f, "{}", self.content),
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -48,6 +189,74 @@ pub struct Presence {
     pub presence: MatrixPresence,
 }
 
+#[derive(Debug, Clone)]
+pub struct Invite {
+    pub inviter: Option<Source>,
+}
+
+/// Member of a room, as shown in the member list.
+#[derive(Debug, Clone)]
+pub struct Member {
+    pub source: Source,
+    pub membership: MatrixMembership,
+    pub power_level: i64,
+}
+
+// Room state keys with a meaning for the UI
+pub const STATE_NAME: &str = "name";
+pub const STATE_TOPIC: &str = "topic";
+// User a direct chat is with
+pub const STATE_DIRECT: &str = "direct";
+
+/// Change of a room state entry. A `None` value removes the entry.
+#[derive(Debug, Clone)]
+pub struct StateChange {
+    pub key: String,
+    pub value: Option<String>,
+}
+
+/// New version of a message.
+#[derive(Debug, Clone)]
+pub struct Edit {
+    pub id: String,
+    pub message: Message,
+}
+
+/// New delivery state of a message we sent, by transaction ID.
+#[derive(Debug, Clone)]
+pub struct DeliveryUpdate {
+    pub txn_id: String,
+    pub delivery: Delivery,
+    // Network identifier of the sent message
+    pub id: Option<String>,
+}
+
+/// Deletion of an event.
+#[derive(Debug, Clone)]
+pub struct Redaction {
+    pub id: String,
+    pub reason: Option<String>,
+}
+
+/// Annotation of a message with a key, usually an emoji.
+#[derive(Debug, Clone)]
+pub struct Reaction {
+    pub id: String,
+    // Annotated message
+    pub target: String,
+    pub key: String,
+    // Sent by the local user
+    pub own: bool,
+}
+
+/// Notifications of a room not read yet.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Unread {
+    pub notifications: u64,
+    // Notifications mentioning us, or otherwise highlighted
+    pub highlights: u64,
+}
+
 #[derive(Debug, Clone)]
 pub struct NewRoom {
     pub id: Option<room::Id>,
@@ -60,10 +269,35 @@ pub struct NewRoom {
 pub enum NetEventKind {
     Connected,
     Disconnected,
-    Invite,
+    // The room is gone and should be removed from the UI
+    Closed,
+    Invite(Invite),
     Message(Message),
+    Edit(Edit),
+    Delivery(DeliveryUpdate),
+    Redaction(Redaction),
+    Reaction(Reaction),
+    // The source read the room up to this event
+    Receipt(String),
+    // Our read marker moved to this event
+    FullyRead(String),
+    Unread(Unread),
+    // Users composing a message in the room
+    Typing(Vec<Source>),
+    // The requested page of older events was delivered, if there was any
+    HistoryLoaded,
+    // Message referenced by the timeline (replied to, ...) but not part of the loaded part of it
+    Referenced(Message),
+    // Informational line about the room (membership or state change)
+    Info(String),
+    // Advancement of a long running request, for the status line
+    Progress(String),
     NewRoom(NewRoom),
     Presence(Presence),
+    // The displayed name of a source changed
+    Rename(Source),
+    State(StateChange),
+    Member(Member),
     Error(String),
     Unknown(Unknown),
 }
@@ -72,7 +306,7 @@ pub enum NetEventKind {
 pub struct NetEvent {
     pub date: usize,
     pub room: room::Id,
-    pub source: Option<String>, // TODO ID instead
+    pub source: Option<Source>,
     pub event: NetEventKind,
 }
 
@@ -84,12 +318,38 @@ impl fmt::Display for NetEventKind {
             match self {
                 NetEventKind::Connected => "Room connected".to_string(),
                 NetEventKind::Disconnected => "Room disconnected".to_string(),
-                NetEventKind::Invite => "Room invitation".to_string(),
-                NetEventKind::Message(ev) => {
-                    ev.content.clone()
-                }
+                NetEventKind::Closed => "Room closed".to_string(),
+                NetEventKind::Invite(i) => match &i.inviter {
+                    Some(inviter) => format!("Room invitation from {}", inviter.name),
+                    None => "Room invitation".to_string(),
+                },
+                NetEventKind::Message(ev) => ev.to_string(),
+                NetEventKind::HistoryLoaded => "History loaded".to_string(),
+                NetEventKind::Referenced(ev) => format!("Referenced message: {}", ev),
+                NetEventKind::Edit(e) => format!("Message {} edited: {}", e.id, e.message),
+                NetEventKind::Delivery(d) => format!("Message {} {:?}", d.txn_id, d.delivery),
+                NetEventKind::Redaction(r) => format!("Event {} deleted", r.id),
+                NetEventKind::Reaction(r) => format!("Reacted {} to {}", r.key, r.target),
+                NetEventKind::Receipt(id) => format!("Read up to {}", id),
+                NetEventKind::FullyRead(id) => format!("Read marker moved to {}", id),
+                NetEventKind::Typing(sources) => format!("{} users typing", sources.len()),
+                NetEventKind::Unread(u) => format!(
+                    "{} unread notifications ({} highlighted)",
+                    u.notifications, u.highlights
+                ),
+                NetEventKind::Info(s) => s.clone(),
+                NetEventKind::Progress(s) => s.clone(),
                 NetEventKind::NewRoom(r) => format!("Spawned room  {:?}", r),
                 NetEventKind::Presence(p) => format!("Presence  {:?}", p),
+                NetEventKind::Rename(s) => format!("{} is now known as {}", s.id, s.name),
+                NetEventKind::State(s) => match &s.value {
+                    Some(value) => format!("{} set to {}", s.key, value),
+                    None => format!("{} removed", s.key),
+                },
+                NetEventKind::Member(m) => format!(
+                    "Member {} ({}): {} (power level {})",
+                    m.source.name, m.source.id, m.membership, m.power_level
+                ),
                 NetEventKind::Error(s) => ["ERROR: ".to_string(), s.clone()].concat(),
                 NetEventKind::Unknown(ev) => ["UNKNOWN EVENT: ", &ev.ty, ": ", &ev.data].concat(),
             }
@@ -98,7 +358,7 @@ impl fmt::Display for NetEventKind {
 }
 
 impl NetEventKind {
-    pub fn to_event(&self, room: room::Id, date: usize, source: Option<String>) -> Event {
+    pub fn to_event(&self, room: room::Id, date: usize, source: Option<Source>) -> Event {
         Event::Net(NetEvent {
             date,
             room,
@@ -107,7 +367,7 @@ impl NetEventKind {
         })
     }
 
-    pub fn to_current_event(&self, room: room::Id, source: Option<String>) -> Event {
+    pub fn to_current_event(&self, room: room::Id, source: Option<Source>) -> Event {
         self.to_event(room, now(), source)
     }
 }
@@ -130,6 +390,22 @@ pub enum Action {
 pub enum CommandAction {
     Connect,
     Disconnect,
+    AcceptInvite,
+    DeclineInvite,
+    Close { forget: bool },
+    // Delete the selected message, with a reason
+    Redact(Option<String>),
+    // Send the selected failed message again, or forget it
+    Retry,
+    Cancel,
+    // Add or remove our reaction to the selected message
+    React(String),
+    // Share a local file, by path
+    Upload(String),
+    // Download the attachment of the selected message, and open it if asked to
+    Download { open: bool },
+    // Read the messages of the current room as markdown or not
+    Markdown(bool),
     NewRoom(room::net::NewRoom),
     Quit,
     Save,
@@ -149,12 +425,24 @@ pub struct RoomPublish {
 #[derive(Debug)]
 pub enum RoomAction {
     Publish(RoomPublish),
+    LoadHistory,
+    LoadMembers,
+    // Fetch a message of a room that is not loaded in its timeline
+    LoadEvent { room: crate::room::Id, id: String },
+    // Mark a room as read up to a message
+    MarkRead { room: crate::room::Id, id: String },
+    // Tell the current room whether we are composing a message
+    Typing(bool),
 }
 
 #[derive(Debug)]
 pub enum AppAction {
     CopyBufferSet(String),
     StatusSet(String),
+    // Start writing a message in the input
+    FocusInput,
+    // Replace the text of the input
+    InputSet(String),
 }
 
 // ==============================================================================================
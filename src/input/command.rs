@@ -1,4 +1,5 @@
 use crate::event::{Action, AppAction, CommandAction, Event, EventProcessor, Key};
+use crate::text::emoji;
 use crate::widget::text::Text;
 use tui::style::Style;
 
@@ -143,6 +144,41 @@ impl Command {
                 }
                 "connect" => vec![Action::Command(CommandAction::Connect)],
                 "disconnect" => vec![Action::Command(CommandAction::Disconnect)],
+                "close" => vec![Action::Command(CommandAction::Close { forget: false })],
+                "forget" => vec![Action::Command(CommandAction::Close { forget: true })],
+                "accept" => vec![Action::Command(CommandAction::AcceptInvite)],
+                "decline" => vec![Action::Command(CommandAction::DeclineInvite)],
+                "redact" => {
+                    let reason = args.join(" ");
+                    let reason = if reason.is_empty() {
+                        None
+                    } else {
+                        Some(reason)
+                    };
+                    vec![Action::Command(CommandAction::Redact(reason))]
+                }
+                "react" if args.is_empty() => vec![Action::App(AppAction::StatusSet(
+                    "Syntax: react <emoji|:shortcode:>".to_string(),
+                ))],
+                "react" => match emoji::reaction_key(&args.join(" ")) {
+                    Ok(key) => vec![Action::Command(CommandAction::React(key))],
+                    Err(e) => vec![Action::App(AppAction::StatusSet(e))],
+                },
+                "retry" => vec![Action::Command(CommandAction::Retry)],
+                "cancel" => vec![Action::Command(CommandAction::Cancel)],
+                "upload" if args.is_empty() => vec![Action::App(AppAction::StatusSet(
+                    "Syntax: upload <path>".to_string(),
+                ))],
+                "upload" => vec![Action::Command(CommandAction::Upload(args.join(" ")))],
+                "download" => vec![Action::Command(CommandAction::Download { open: false })],
+                "open" => vec![Action::Command(CommandAction::Download { open: true })],
+                "markdown" => match args.as_slice() {
+                    ["on"] => vec![Action::Command(CommandAction::Markdown(true))],
+                    ["off"] => vec![Action::Command(CommandAction::Markdown(false))],
+                    _ => vec![Action::App(AppAction::StatusSet(
+                        "Syntax: markdown on|off".to_string(),
+                    ))],
+                },
                 _ => {
                     unknown_cmd = true;
                     vec![]
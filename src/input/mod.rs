@@ -1,7 +1,12 @@
-use crate::event::{Action, AppAction, Event, EventProcessor, Key};
+use crate::event::{Action, AppAction, Event, EventProcessor, Key, RoomAction};
 use crate::widget::{text::Text, Height};
+use std::time::{Duration, Instant};
 
 pub mod command;
+pub mod slash;
+
+// Interval at which the typing notification is renewed while composing
+const TYPING_RENEWAL: Duration = Duration::from_secs(10);
 
 pub enum Mode {
     None,
@@ -27,6 +32,8 @@ pub struct Input {
     mode: Mode,
     pub text_widget: Text,
     focused: bool,
+    // When we last told the room we were typing
+    typing: Option<Instant>,
 }
 
 impl Default for Input {
@@ -35,6 +42,7 @@ impl Default for Input {
             mode: Mode::None,
             text_widget: Text::new(&""),
             focused: false,
+            typing: None,
         }
     }
 }
@@ -57,6 +65,31 @@ impl Input {
         self.text_widget.show_cursor = focused;
     }
 
+    /// Replace the text, moving the cursor at its end.
+    pub fn set_text(&mut self, text: &str) {
+        self.text_widget.set_text(text);
+        self.text_widget.text.cursor.line = self.text_widget.text.lines.len() - 1;
+        self.text_widget.text.end();
+    }
+
+    /// Tell the room we are typing, unless we did it recently.
+    fn typing(&mut self) -> Vec<Action> {
+        match self.typing {
+            Some(since) if since.elapsed() < TYPING_RENEWAL => vec![],
+            _ => {
+                self.typing = Some(Instant::now());
+                vec![Action::Room(RoomAction::Typing(true))]
+            }
+        }
+    }
+
+    /// Tell the room we stopped typing, if we told it we were.
+    pub fn stop_typing(&mut self) -> Option<Action> {
+        self.typing
+            .take()
+            .map(|_| Action::Room(RoomAction::Typing(false)))
+    }
+
     pub fn set_append_mode(&mut self) {
         if let Mode::None = self.mode {
             self.set_insert_mode();
@@ -118,8 +151,14 @@ impl Input {
     fn process_insert_event(&mut self, event: Event) -> Vec<Action> {
         match event {
             Event::Key(k) => match k {
-                Key::Char(c) => self.text_widget.text.insert(c),
-                Key::Backspace => self.text_widget.text.backspace(),
+                Key::Char(c) => {
+                    self.text_widget.text.insert(c);
+                    return self.typing();
+                }
+                Key::Backspace => {
+                    self.text_widget.text.backspace();
+                    return self.typing();
+                }
                 Key::Up => self.text_widget.text.up(),
                 Key::Down => self.text_widget.text.down(),
                 Key::Right => self.text_widget.text.right(),
@@ -129,7 +168,9 @@ impl Input {
                 Key::Esc => {
                     self.text_widget.text.left();
                     self.mode = Mode::None;
-                    return vec![Action::FocusLoss];
+                    let mut actions = vec![Action::FocusLoss];
+                    actions.extend(self.stop_typing());
+                    return actions;
                 }
                 x => eprintln!("_ = {:?}", x),
             },
@@ -141,7 +182,10 @@ impl Input {
     fn process_replace_event(&mut self, event: Event) -> Vec<Action> {
         match event {
             Event::Key(k) => match k {
-                Key::Char(c) => self.text_widget.text.replace(c),
+                Key::Char(c) => {
+                    self.text_widget.text.replace(c);
+                    return self.typing();
+                }
                 Key::Up => self.text_widget.text.up(),
                 Key::Down => self.text_widget.text.down(),
                 Key::Right => self.text_widget.text.right(),
@@ -151,7 +195,9 @@ impl Input {
                 Key::Esc => {
                     self.text_widget.text.left();
                     self.mode = Mode::None;
-                    return vec![Action::FocusLoss];
+                    let mut actions = vec![Action::FocusLoss];
+                    actions.extend(self.stop_typing());
+                    return actions;
                 }
                 _ => (),
             },
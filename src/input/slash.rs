@@ -0,0 +1,96 @@
+use crate::room::net::{MessageType, Publish};
+use crate::text::markdown;
+
+const SHRUG: &str = r"¯\_(ツ)_/¯";
+
+/// Message of the given type, with an HTML version when `markdown` is set and it has formatting.
+pub fn message(msgtype: MessageType, body: &str, markdown: bool) -> Publish {
+    let mut publish = Publish::new(msgtype, body.to_string());
+    if markdown {
+        publish.formatted_body = markdown::to_html(body);
+    }
+    publish
+}
+
+/// `s/<pattern>/<replacement>/[g]` correction of the last message sent, as on IRC.
+#[derive(Debug)]
+pub struct Substitution {
+    pattern: String,
+    replacement: String,
+    global: bool,
+}
+
+impl Substitution {
+    pub fn parse(text: &str) -> Option<Self> {
+        let rest = text.strip_prefix("s/")?;
+        let mut parts = vec![String::new()];
+        let mut chars = rest.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => match chars.next() {
+                    Some('/') => parts.last_mut().unwrap().push('/'),
+                    Some(c) => parts.last_mut().unwrap().extend(&['\\', c]),
+                    None => parts.last_mut().unwrap().push('\\'),
+                },
+                '/' => parts.push(String::new()),
+                c => parts.last_mut().unwrap().push(c),
+            }
+        }
+        match parts.as_slice() {
+            [pattern, replacement, flags]
+                if !pattern.is_empty() && ["", "g"].contains(&flags.as_str()) =>
+            {
+                Some(Self {
+                    pattern: pattern.clone(),
+                    replacement: replacement.clone(),
+                    global: flags == "g",
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// Corrected text, or `None` when the pattern is not found in it.
+    pub fn apply(&self, text: &str) -> Option<String> {
+        if !text.contains(&self.pattern) {
+            None
+        } else if self.global {
+            Some(text.replace(&self.pattern, &self.replacement))
+        } else {
+            Some(text.replacen(&self.pattern, &self.replacement, 1))
+        }
+    }
+}
+
+/// Turn the text of the message input into a message, interpreting IRC style `/` commands.
+///
+/// The text is read as CommonMark when `markdown` is set. A leading `//` escapes the command
+/// prefix.
+pub fn parse(text: &str, markdown: bool) -> Result<Publish, String> {
+    if !text.starts_with('/') {
+        return Ok(message(MessageType::Text, text, markdown));
+    }
+    if text.starts_with("//") {
+        return Ok(message(MessageType::Text, &text[1..], markdown));
+    }
+
+    let mut parts = text[1..].splitn(2, |c: char| c.is_whitespace());
+    let command = parts.next().unwrap_or("");
+    let args = parts.next().unwrap_or("").trim_start();
+    match command {
+        "me" if !args.is_empty() => Ok(message(MessageType::Emote, args, markdown)),
+        "notice" if !args.is_empty() => Ok(message(MessageType::Notice, args, markdown)),
+        "plain" if !args.is_empty() => Ok(message(MessageType::Text, args, false)),
+        "me" | "notice" | "plain" => Err(format!("Syntax: /{} <message>", command)),
+        // The shrug is no markdown
+        "shrug" if args.is_empty() => Ok(Publish::new(MessageType::Text, SHRUG.to_string())),
+        "shrug" => Ok(Publish::new(
+            MessageType::Text,
+            format!("{} {}", args, SHRUG),
+        )),
+        command => Err(format!(
+            "Unknown command '/{}' (start the message with // to send it as is)",
+            command
+        )),
+    }
+}
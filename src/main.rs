@@ -1,4 +1,5 @@
 pub mod app;
+pub mod dirs;
 pub mod event;
 pub mod input;
 pub mod io;
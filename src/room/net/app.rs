@@ -1,7 +1,7 @@
-use crate::event::{self, Event, Message, NetEventKind};
+use crate::event::{self, Event, Message, MessageKind, NetEventKind, Source};
 use crate::room::{
     self,
-    net::{Action, ActionKind},
+    net::{Action, ActionKind, MessageType},
 };
 use crate::sequence_number::SequenceNumber;
 use std::sync::Arc;
@@ -39,7 +39,7 @@ impl App {
     }
 
     async fn send_current_by_me(&mut self, event: NetEventKind) {
-        self.send(event.to_current_event(self.id.clone(), Some("Me".to_string())))
+        self.send(event.to_current_event(self.id, Some(Source::new("Me", "Me"))))
             .await;
     }
 
@@ -61,8 +61,19 @@ impl App {
                         self.send_error("Cannot disconnect from main room (it is a local room)")
                             .await
                     }
-                    ActionKind::Publish(packet) => {
-                        self.send_current_by_me(NetEventKind::Message(Message { content: packet }))
+                    ActionKind::Close { .. } => {
+                        self.send_error("Cannot close the main room (it is a local room)")
+                            .await
+                    }
+                    ActionKind::Publish(publish) => {
+                        let kind = match publish.msgtype {
+                            MessageType::Text => MessageKind::Text,
+                            MessageType::Emote => MessageKind::Emote,
+                            MessageType::Notice => MessageKind::Notice,
+                        };
+                        let mut message = Message::new(kind, publish.body);
+                        message.own = true;
+                        self.send_current_by_me(NetEventKind::Message(message))
                             .await
                     }
                     ActionKind::NewRoom(room) => match self.spawn(room).await {
@@ -72,11 +83,31 @@ impl App {
                             self.send_error(&error).await
                         }
                     },
-                    ActionKind::Sync => {
-                        self.send_error(
-                            "Thou shall stop bothering local residents with syncing matter",
-                        )
-                        .await
+                    // Local rooms keep their whole history
+                    ActionKind::LoadHistory => self.send_current(NetEventKind::HistoryLoaded).await,
+                    // Local rooms have no members
+                    ActionKind::LoadMembers => (),
+                    // Local rooms hold no references
+                    ActionKind::LoadEvent(_) => (),
+                    ActionKind::MarkRead(_) => (),
+                    // Local messages cannot fail
+                    ActionKind::Retry(_)
+                    | ActionKind::Cancel(_)
+                    | ActionKind::EditQueued { .. } => (),
+                    ActionKind::Typing(_) => (),
+                    ActionKind::Redact { .. } => {
+                        self.send_error("Cannot delete the messages of the main room")
+                            .await
+                    }
+                    ActionKind::React { .. } => {
+                        self.send_error("Cannot react to the messages of the main room")
+                            .await
+                    }
+                    ActionKind::Upload(_) => {
+                        self.send_error("Cannot share files in the main room").await
+                    }
+                    ActionKind::Download { .. } => {
+                        self.send_error("The main room holds no attachments").await
                     }
                 }
             }
@@ -118,7 +149,7 @@ impl App {
                             .to_string()
                             .parse()
                             .map_err(|e| format!("{}", e))?,
-                        sync_period: 8024,
+                        sync_timeout: 30_000,
                         credentials,
                     },
                     room::ServerHandle {
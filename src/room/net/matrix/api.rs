@@ -0,0 +1,233 @@
+//! Client-server API endpoints missing from `ruma_client_api`.
+
+pub mod join_room_by_id_or_alias {
+    //! [POST /_matrix/client/r0/join/{roomIdOrAlias}](https://matrix.org/docs/spec/client_server/r0.6.0#post-matrix-client-r0-join-roomidoralias)
+    //! with the `server_name` routing hints.
+
+    use ruma_api::ruma_api;
+    use ruma_identifiers::{RoomId, RoomIdOrAliasId};
+
+    ruma_api! {
+        metadata {
+            description: "Join a room using its ID or one of its aliases.",
+            method: POST,
+            name: "join_room_by_id_or_alias",
+            path: "/_matrix/client/r0/join/:room_id_or_alias",
+            rate_limited: true,
+            requires_authentication: true,
+        }
+
+        request {
+            /// The room to join.
+            #[ruma_api(path)]
+            pub room_id_or_alias: RoomIdOrAliasId,
+
+            /// Servers to attempt to join the room through, as `("server_name", <server>)` pairs.
+            #[ruma_api(query_map)]
+            pub server_name: Vec<(String, String)>,
+        }
+
+        response {
+            /// The room that the user joined.
+            pub room_id: RoomId,
+        }
+    }
+}
+
+pub mod create_room {
+    //! [POST /_matrix/client/r0/createRoom](https://matrix.org/docs/spec/client_server/r0.6.0#post-matrix-client-r0-createroom)
+    //! with the initial state and the direct chat flag.
+
+    use ruma_api::ruma_api;
+    pub use ruma_client_api::r0::room::{create_room::RoomPreset, Visibility};
+    use ruma_identifiers::{RoomId, UserId};
+    use serde_json::Value;
+
+    ruma_api! {
+        metadata {
+            description: "Create a new room.",
+            method: POST,
+            name: "create_room",
+            path: "/_matrix/client/r0/createRoom",
+            rate_limited: false,
+            requires_authentication: true,
+        }
+
+        request {
+            /// State events to set in the new room, as `{"type", "state_key", "content"}` objects.
+            #[serde(skip_serializing_if = "Vec::is_empty")]
+            pub initial_state: Vec<Value>,
+
+            /// A list of user IDs to invite to the room.
+            #[serde(skip_serializing_if = "Vec::is_empty")]
+            pub invite: Vec<UserId>,
+
+            /// Flag the invitations as being for a direct chat.
+            #[serde(skip_serializing_if = "std::ops::Not::not")]
+            pub is_direct: bool,
+
+            /// If this is included, an `m.room.name` event will be sent into the room.
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub name: Option<String>,
+
+            /// Convenience parameter for setting various default state events.
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub preset: Option<RoomPreset>,
+
+            /// The desired room alias local part.
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub room_alias_name: Option<String>,
+
+            /// If this is included, an `m.room.topic` event will be sent into the room.
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub topic: Option<String>,
+
+            /// Whether the room is listed in the room directory.
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub visibility: Option<Visibility>,
+        }
+
+        response {
+            /// The created room's ID.
+            pub room_id: RoomId,
+        }
+    }
+}
+
+pub mod get_global_account_data {
+    //! [GET /_matrix/client/r0/user/{userId}/account_data/{type}](https://matrix.org/docs/spec/client_server/r0.6.0#get-matrix-client-r0-user-userid-account-data-type)
+
+    use ruma_api::ruma_api;
+    use ruma_identifiers::UserId;
+    use serde_json::Value;
+
+    ruma_api! {
+        metadata {
+            description: "Gets global account data.",
+            method: GET,
+            name: "get_global_account_data",
+            path: "/_matrix/client/r0/user/:user_id/account_data/:event_type",
+            rate_limited: false,
+            requires_authentication: true,
+        }
+
+        request {
+            /// The ID of the user to get account data for.
+            #[ruma_api(path)]
+            pub user_id: UserId,
+
+            /// The event type of the account data to get.
+            #[ruma_api(path)]
+            pub event_type: String,
+        }
+
+        response {
+            /// The account data content.
+            #[ruma_api(body)]
+            pub data: Value,
+        }
+    }
+}
+
+pub mod send_message_event {
+    //! [PUT /_matrix/client/r0/rooms/{roomId}/send/{eventType}/{txnId}](https://matrix.org/docs/spec/client_server/r0.6.0#put-matrix-client-r0-rooms-roomid-send-eventtype-txnid)
+    //! with a raw JSON content, for the message kinds and relations our event types lack.
+
+    use ruma_api::ruma_api;
+    use ruma_identifiers::{EventId, RoomId};
+    use serde_json::Value;
+
+    ruma_api! {
+        metadata {
+            description: "Send a message event to a room.",
+            method: PUT,
+            name: "send_message_event",
+            path: "/_matrix/client/r0/rooms/:room_id/send/:event_type/:txn_id",
+            rate_limited: false,
+            requires_authentication: true,
+        }
+
+        request {
+            /// The room to send the event to.
+            #[ruma_api(path)]
+            pub room_id: RoomId,
+
+            /// The type of event to send.
+            #[ruma_api(path)]
+            pub event_type: String,
+
+            /// The transaction ID for this event.
+            #[ruma_api(path)]
+            pub txn_id: String,
+
+            /// The event's content.
+            #[ruma_api(body)]
+            pub data: Value,
+        }
+
+        response {
+            /// A unique identifier for the event.
+            pub event_id: EventId,
+        }
+    }
+}
+
+pub mod sync_events {
+    //! [GET /_matrix/client/r0/sync](https://matrix.org/docs/spec/client_server/r0.6.0#get-matrix-client-r0-sync)
+    //! with the global account data.
+
+    use js_int::UInt;
+    use ruma_api::ruma_api;
+    pub use ruma_client_api::r0::sync::sync_events::{
+        Filter, IncomingPresence, IncomingRooms, Presence, Rooms, SetPresence,
+    };
+    use serde_json::Value;
+
+    ruma_api! {
+        metadata {
+            description: "Get all new events from all rooms since the last sync or a given point of time.",
+            method: GET,
+            name: "sync",
+            path: "/_matrix/client/r0/sync",
+            rate_limited: false,
+            requires_authentication: true,
+        }
+
+        request {
+            /// A filter represented either as its full JSON definition or the ID of a saved filter.
+            #[serde(skip_serializing_if = "Option::is_none")]
+            #[ruma_api(query)]
+            pub filter: Option<Filter>,
+            /// A point in time to continue a sync from.
+            #[serde(skip_serializing_if = "Option::is_none")]
+            #[ruma_api(query)]
+            pub since: Option<String>,
+            /// Controls whether to include the full state for all rooms the user is a member of.
+            #[serde(skip_serializing_if = "Option::is_none")]
+            #[ruma_api(query)]
+            pub full_state: Option<bool>,
+            /// Controls whether the client is automatically marked as online by polling this API.
+            #[serde(skip_serializing_if = "Option::is_none")]
+            #[ruma_api(query)]
+            pub set_presence: Option<SetPresence>,
+            /// The maximum time to poll in milliseconds before returning this request.
+            #[serde(skip_serializing_if = "Option::is_none")]
+            #[ruma_api(query)]
+            pub timeout: Option<UInt>,
+        }
+
+        response {
+            /// The batch token to supply in the `since` param of the next `/sync` request.
+            pub next_batch: String,
+            /// Updates to rooms.
+            #[wrap_incoming]
+            pub rooms: Rooms,
+            /// Updates to the presence status of other users.
+            #[wrap_incoming]
+            pub presence: Presence,
+            /// The global private data created by this user, as `{"events": [...]}`.
+            #[serde(default)]
+            pub account_data: Value,
+        }
+    }
+}
@@ -0,0 +1,131 @@
+use super::MatrixPresence;
+use crate::dirs;
+use ruma_identifiers::UserId;
+use std::convert::TryFrom;
+use std::path::PathBuf;
+
+const CREATE_SYNTAX: &str = "Syntax: create [--name <name>] [--topic <topic>] \
+    [--alias <localpart>] [--public|--private] [--invite <@user:server>]... [--encrypted]";
+const DM_SYNTAX: &str = "Syntax: dm <@user:server>";
+const PRESENCE_SYNTAX: &str = "Syntax: presence online|unavailable|offline";
+const DOWNLOADS_SYNTAX: &str = "Syntax: downloads <directory>";
+
+// =============================================================================
+// Server room commands
+// =============================================================================
+#[derive(Debug, Default)]
+pub struct CreateRoom {
+    pub name: Option<String>,
+    pub topic: Option<String>,
+    pub alias: Option<String>,
+    pub public: bool,
+    pub invite: Vec<UserId>,
+    pub encrypted: bool,
+}
+
+#[derive(Debug)]
+pub enum Command {
+    Create(CreateRoom),
+    Dm(UserId),
+    Presence(MatrixPresence),
+    // Status message shown with our presence, none to clear it
+    Status(Option<String>),
+    // Directory the attachments are downloaded to
+    Downloads(PathBuf),
+    // Program and arguments the downloaded attachments are opened with, none not to open them
+    Opener(Option<Vec<String>>),
+}
+
+/// Split a command line on spaces, keeping double quoted text together.
+fn split(line: &str) -> Vec<String> {
+    let mut words = vec![];
+    let mut word = String::new();
+    let mut quoted = false;
+    let mut started = false;
+    for c in line.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                started = true;
+            }
+            ' ' if !quoted => {
+                if started {
+                    words.push(std::mem::take(&mut word));
+                    started = false;
+                }
+            }
+            c => {
+                word.push(c);
+                started = true;
+            }
+        }
+    }
+    if started {
+        words.push(word);
+    }
+    words
+}
+
+fn parse_user(user: &str) -> Result<UserId, String> {
+    UserId::try_from(user).map_err(|e| format!("Bad user ID '{}': {}", user, e))
+}
+
+pub fn parse(line: &str) -> Result<Command, String> {
+    let mut words = split(line).into_iter();
+    match words.next().as_deref() {
+        Some("create") => {
+            let mut options = CreateRoom::default();
+            while let Some(option) = words.next() {
+                let mut value = || words.next().ok_or_else(|| CREATE_SYNTAX.to_string());
+                match option.as_str() {
+                    "--name" => options.name = Some(value()?),
+                    "--topic" => options.topic = Some(value()?),
+                    "--alias" => options.alias = Some(value()?),
+                    "--invite" => options.invite.push(parse_user(&value()?)?),
+                    "--public" => options.public = true,
+                    "--private" => options.public = false,
+                    "--encrypted" => options.encrypted = true,
+                    option => {
+                        return Err(format!("Unknown option '{}'. {}", option, CREATE_SYNTAX))
+                    }
+                }
+            }
+            Ok(Command::Create(options))
+        }
+        Some("dm") => match (words.next(), words.next()) {
+            (Some(user), None) => Ok(Command::Dm(parse_user(&user)?)),
+            _ => Err(DM_SYNTAX.to_string()),
+        },
+        Some("presence") => match (words.next().as_deref(), words.next()) {
+            (Some("online"), None) => Ok(Command::Presence(MatrixPresence::Online)),
+            (Some("unavailable"), None) => Ok(Command::Presence(MatrixPresence::Unavailable)),
+            (Some("offline"), None) => Ok(Command::Presence(MatrixPresence::Offline)),
+            _ => Err(PRESENCE_SYNTAX.to_string()),
+        },
+        Some("status") => {
+            let status: Vec<_> = words.collect();
+            if status.is_empty() {
+                Ok(Command::Status(None))
+            } else {
+                Ok(Command::Status(Some(status.join(" "))))
+            }
+        }
+        Some("downloads") => match (words.next(), words.next()) {
+            (Some(dir), None) => Ok(Command::Downloads(dirs::expand_home(&dir))),
+            _ => Err(DOWNLOADS_SYNTAX.to_string()),
+        },
+        Some("opener") => {
+            let opener: Vec<_> = words.collect();
+            if opener.is_empty() {
+                Ok(Command::Opener(None))
+            } else {
+                Ok(Command::Opener(Some(opener)))
+            }
+        }
+        Some(cmd) => Err(format!("Unsupported command: {}", cmd)),
+        None => Err(format!(
+            "Available commands: create, dm, presence, status, downloads, opener. {}",
+            CREATE_SYNTAX
+        )),
+    }
+}
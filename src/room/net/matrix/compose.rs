@@ -0,0 +1,162 @@
+use super::media::LocalFile;
+use crate::event::{AttachmentKind, Delivery, Message, MessageKind};
+use crate::room::net::{MessageType, Publish, Relation, ReplyTo};
+use crate::text::html;
+use ruma_identifiers::RoomId;
+use serde_json::{json, Value};
+
+pub const MESSAGE_EVENT_TYPE: &str = "m.room.message";
+// Format of the formatted bodies we send and render
+pub const HTML_FORMAT: &str = "org.matrix.custom.html";
+const MATRIX_TO_PREFIX: &str = "https://matrix.to/#/";
+// Relation of the edits to the message they replace
+pub const REPLACE_RELATION: &str = "m.replace";
+pub const REACTION_EVENT_TYPE: &str = "m.reaction";
+// Relation of the reactions to the message they annotate
+pub const ANNOTATION_RELATION: &str = "m.annotation";
+
+// =============================================================================
+// Outgoing messages
+// =============================================================================
+/// HTML version of a plain text.
+fn plain_html(text: &str) -> String {
+    html::escape(text).replace('\n', "<br>")
+}
+
+/// Quote of the replied message put in front of a reply, for the clients not supporting replies.
+fn reply_fallback(room_id: &RoomId, reply: &ReplyTo) -> (String, String) {
+    let mut body = String::new();
+    for (i, line) in reply.body.lines().enumerate() {
+        if i == 0 {
+            body.push_str(&format!("> <{}> {}\n", reply.sender, line));
+        } else {
+            body.push_str(&format!("> {}\n", line));
+        }
+    }
+    body.push('\n');
+
+    let quoted = match reply.formatted.as_ref() {
+        Some(html) => html.clone(),
+        None => plain_html(&reply.body),
+    };
+    let html = format!(
+        "<mx-reply><blockquote><a href=\"{0}{1}/{2}\">In reply to</a> \
+         <a href=\"{0}{3}\">{3}</a><br>{4}</blockquote></mx-reply>",
+        MATRIX_TO_PREFIX,
+        room_id,
+        reply.id,
+        html::escape(&reply.sender),
+        quoted
+    );
+    (body, html)
+}
+
+/// Content of the `m.room.message` event publishing a message in a room.
+pub fn message_content(room_id: &RoomId, publish: &Publish) -> Value {
+    let msgtype = match publish.msgtype {
+        MessageType::Text => "m.text",
+        MessageType::Emote => "m.emote",
+        MessageType::Notice => "m.notice",
+    };
+    let mut content = json!({
+        "msgtype": msgtype,
+        "body": publish.body,
+    });
+    if let Some(html) = publish.formatted_body.as_ref() {
+        content["format"] = json!(HTML_FORMAT);
+        content["formatted_body"] = json!(html);
+    }
+    match publish.relation.as_ref() {
+        Some(Relation::InReplyTo(reply)) => {
+            let (body, html) = reply_fallback(room_id, reply);
+            let formatted = match publish.formatted_body.as_ref() {
+                Some(formatted) => formatted.clone(),
+                None => plain_html(&publish.body),
+            };
+            content["body"] = json!(body + &publish.body);
+            content["format"] = json!(HTML_FORMAT);
+            content["formatted_body"] = json!(html + &formatted);
+            content["m.relates_to"] = json!({ "m.in_reply_to": { "event_id": reply.id } });
+        }
+        Some(Relation::Replace(id)) => {
+            // Clients not supporting edits show the new version as a new message
+            let new_content = content.clone();
+            content["body"] = json!(format!("* {}", publish.body));
+            if let Some(html) = publish.formatted_body.as_ref() {
+                content["formatted_body"] = json!(format!("* {}", html));
+            }
+            content["m.new_content"] = new_content;
+            content["m.relates_to"] = json!({ "rel_type": REPLACE_RELATION, "event_id": id });
+        }
+        None => (),
+    }
+    content
+}
+
+/// Message shown until a published message comes back from the server, edits excepted.
+pub fn local_echo(publish: &Publish, txn_id: &str) -> Option<Message> {
+    let kind = match publish.msgtype {
+        MessageType::Text => MessageKind::Text,
+        MessageType::Emote => MessageKind::Emote,
+        MessageType::Notice => MessageKind::Notice,
+    };
+    let mut message = Message::new(kind, publish.body.clone());
+    message.formatted = publish.formatted_body.clone();
+    match publish.relation.as_ref() {
+        Some(Relation::InReplyTo(reply)) => message.in_reply_to = Some(reply.id.clone()),
+        Some(Relation::Replace(_)) => return None,
+        None => (),
+    }
+    message.own = true;
+    message.txn_id = Some(txn_id.to_string());
+    message.delivery = Some(Delivery::Queued);
+    Some(message)
+}
+
+/// Content of the message sharing an uploaded file, with its thumbnail if it has one.
+pub fn attachment_content(file: &LocalFile, url: &str, thumbnail_url: Option<&str>) -> Value {
+    let msgtype = match file.kind {
+        AttachmentKind::Image => "m.image",
+        AttachmentKind::File => "m.file",
+        AttachmentKind::Audio => "m.audio",
+        AttachmentKind::Video => "m.video",
+    };
+    let mut info = json!({
+        "mimetype": file.mimetype,
+        "size": file.size,
+    });
+    if let Some((w, h)) = file.dimensions {
+        info["w"] = json!(w);
+        info["h"] = json!(h);
+    }
+    if let (Some(thumbnail), Some(thumbnail_url)) = (file.thumbnail.as_ref(), thumbnail_url) {
+        info["thumbnail_url"] = json!(thumbnail_url);
+        info["thumbnail_info"] = json!({
+            "mimetype": thumbnail.mimetype,
+            "size": thumbnail.size,
+            "w": thumbnail.width,
+            "h": thumbnail.height,
+        });
+    }
+    let mut content = json!({
+        "msgtype": msgtype,
+        "body": file.name,
+        "url": url,
+        "info": info,
+    });
+    if let AttachmentKind::File = file.kind {
+        content["filename"] = json!(file.name);
+    }
+    content
+}
+
+/// Content of the `m.reaction` event annotating a message with `key`.
+pub fn reaction_content(event_id: &str, key: &str) -> Value {
+    json!({
+        "m.relates_to": {
+            "rel_type": ANNOTATION_RELATION,
+            "event_id": event_id,
+            "key": key,
+        }
+    })
+}
@@ -0,0 +1,68 @@
+use rand::Rng;
+use std::fmt;
+use std::time::Duration;
+
+const BACKOFF_BASE: Duration = Duration::from_secs(1);
+const BACKOFF_MAX: Duration = Duration::from_secs(300);
+
+// =============================================================================
+// Connection state
+// =============================================================================
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum State {
+    LoggedOut,
+    Connecting,
+    Connected,
+    BackingOff,
+}
+
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                State::LoggedOut => "logged out",
+                State::Connecting => "connecting",
+                State::Connected => "connected",
+                State::BackingOff => "backing off",
+            }
+        )
+    }
+}
+
+// =============================================================================
+// Retry delays
+// =============================================================================
+/// Exponential backoff with jitter between consecutive connection attempts.
+#[derive(Debug, Default)]
+pub struct Backoff {
+    attempt: u32,
+}
+
+impl Backoff {
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+
+    /// Number of failed attempts since the last success.
+    pub fn attempts(&self) -> u32 {
+        self.attempt
+    }
+
+    /// Delay before the next attempt. A delay imposed by the server takes precedence.
+    pub fn next_delay(&mut self, retry_after: Option<Duration>) -> Duration {
+        let attempt = self.attempt;
+        self.attempt = self.attempt.saturating_add(1);
+        if let Some(delay) = retry_after {
+            return delay;
+        }
+
+        let delay = BACKOFF_BASE
+            .checked_mul(1 << u32::min(attempt, 16))
+            .map_or(BACKOFF_MAX, |d| Duration::min(d, BACKOFF_MAX));
+        // Keep at least half of the delay so that clients do not hammer the server
+        let half = delay.as_millis() as u64 / 2;
+        Duration::from_millis(half + rand::thread_rng().gen_range(0, half + 1))
+    }
+}
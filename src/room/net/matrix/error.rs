@@ -0,0 +1,116 @@
+use ruma_api::error::FromHttpResponseError;
+use std::fmt;
+use std::time::Duration;
+
+// =============================================================================
+// Homeserver errors
+// =============================================================================
+/// Client error with the Matrix error body decoded when the homeserver sent one.
+#[derive(Debug)]
+pub struct ApiError {
+    pub status: Option<u16>,
+    pub errcode: Option<String>,
+    pub message: String,
+    pub retry_after_ms: Option<u64>,
+}
+
+impl ApiError {
+    /// The homeserver does not accept our access token anymore.
+    pub fn is_unknown_token(&self) -> bool {
+        match self.errcode.as_deref() {
+            Some("M_UNKNOWN_TOKEN") | Some("M_MISSING_TOKEN") => true,
+            _ => self.status == Some(401),
+        }
+    }
+
+    /// The request may succeed later without any change on our side.
+    pub fn is_transient(&self) -> bool {
+        match self.status {
+            None => true,
+            Some(429) => true,
+            Some(status) => status >= 500,
+        }
+    }
+
+    /// Delay requested by the server before retrying (`M_LIMIT_EXCEEDED`).
+    pub fn retry_after(&self) -> Option<Duration> {
+        self.retry_after_ms.map(Duration::from_millis)
+    }
+
+    /// Error response of the homeserver.
+    pub fn from_response(status: u16, body: &[u8]) -> Self {
+        let body: serde_json::Value =
+            serde_json::from_slice(body).unwrap_or(serde_json::Value::Null);
+        let errcode = body["errcode"].as_str().map(|s| s.to_string());
+        let retry_after_ms = body["retry_after_ms"].as_u64();
+        let message = match body["error"].as_str() {
+            Some(s) => s.to_string(),
+            None => format!("HTTP error {}", status),
+        };
+        Self {
+            status: Some(status),
+            errcode,
+            message,
+            retry_after_ms,
+        }
+    }
+
+    /// Failure without any error response of the homeserver.
+    pub fn other<E: fmt::Display>(e: E) -> Self {
+        Self {
+            status: None,
+            errcode: None,
+            message: e.to_string(),
+            retry_after_ms: None,
+        }
+    }
+}
+
+impl From<ruma_client::Error> for ApiError {
+    fn from(e: ruma_client::Error) -> Self {
+        match e {
+            ruma_client::Error::FromHttpResponse(FromHttpResponseError::Http(e)) => {
+                let response = e.into_raw_reponse();
+                Self::from_response(response.status().as_u16(), response.body())
+            }
+            e => Self::other(e),
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.errcode {
+            Some(code) => write!(f, "{}: {}", code, self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+/// Failed attempt at opening a session on the homeserver.
+#[derive(Debug)]
+pub enum ConnectError {
+    /// Worth retrying later (network failure, rate limiting, server error).
+    Transient(ApiError),
+    /// Requires an action from the user.
+    Fatal(String),
+}
+
+impl From<ApiError> for ConnectError {
+    fn from(e: ApiError) -> Self {
+        if e.is_transient() {
+            Self::Transient(e)
+        } else {
+            Self::Fatal(e.to_string())
+        }
+    }
+}
+
+impl fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Transient(e) => write!(f, "{}", e),
+            Self::Fatal(e) => write!(f, "{}", e),
+        }
+    }
+}
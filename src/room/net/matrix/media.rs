@@ -0,0 +1,493 @@
+use super::error::ApiError;
+use crate::event::AttachmentKind;
+use futures_util::StreamExt;
+use image::{GenericImageView, ImageFormat, ImageOutputFormat};
+use std::fs;
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+
+// Largest thumbnail sent with an image, as recommended by the specification
+const THUMBNAIL_WIDTH: u32 = 800;
+const THUMBNAIL_HEIGHT: u32 = 600;
+// Bytes handed to the connection at once while uploading
+const UPLOAD_CHUNK: usize = 64 * 1024;
+
+const DEFAULT_MIMETYPE: &str = "application/octet-stream";
+
+// MIME types by leading bytes, `?` matching any byte
+const SIGNATURES: &[(&[u8], &str)] = &[
+    (b"\x89PNG\r\n\x1a\n", "image/png"),
+    (b"\xff\xd8\xff", "image/jpeg"),
+    (b"GIF87a", "image/gif"),
+    (b"GIF89a", "image/gif"),
+    (b"RIFF????WEBP", "image/webp"),
+    (b"RIFF????WAVE", "audio/wav"),
+    (b"OggS", "audio/ogg"),
+    (b"fLaC", "audio/flac"),
+    (b"ID3", "audio/mpeg"),
+    (b"\x1a\x45\xdf\xa3", "video/webm"),
+    (b"%PDF-", "application/pdf"),
+    (b"PK\x03\x04", "application/zip"),
+    (b"\x1f\x8b", "application/gzip"),
+];
+
+// MIME types of ISO base media files (`????ftyp` signature), by major brand
+const BRANDS: &[(&[u8], &str)] = &[
+    (b"M4A ", "audio/mp4"),
+    (b"M4B ", "audio/mp4"),
+    (b"qt  ", "video/quicktime"),
+    (b"heic", "image/heic"),
+    (b"heix", "image/heic"),
+    (b"mif1", "image/heic"),
+    (b"avif", "image/avif"),
+];
+// Most ISO base media files are MP4 videos
+const ISO_MEDIA_MIMETYPE: &str = "video/mp4";
+
+// MIME types by lower case file extension, for the formats without a signature
+const EXTENSIONS: &[(&str, &str)] = &[
+    ("txt", "text/plain"),
+    ("md", "text/markdown"),
+    ("html", "text/html"),
+    ("htm", "text/html"),
+    ("csv", "text/csv"),
+    ("json", "application/json"),
+    ("svg", "image/svg+xml"),
+    ("mp3", "audio/mpeg"),
+    ("opus", "audio/opus"),
+    ("m4a", "audio/mp4"),
+    ("mkv", "video/x-matroska"),
+    ("mov", "video/quicktime"),
+    ("tar", "application/x-tar"),
+];
+
+// =============================================================================
+// Local files
+// =============================================================================
+/// Image sent along with a larger one, for clients to preview it.
+#[derive(Debug)]
+pub struct Thumbnail {
+    pub data: Vec<u8>,
+    pub size: usize,
+    pub mimetype: String,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// File to share, with what can be told of it without the help of the server.
+#[derive(Debug)]
+pub struct LocalFile {
+    pub name: String,
+    pub mimetype: String,
+    pub kind: AttachmentKind,
+    pub data: Vec<u8>,
+    pub size: usize,
+    // Width and height of images
+    pub dimensions: Option<(u32, u32)>,
+    pub thumbnail: Option<Thumbnail>,
+}
+
+impl LocalFile {
+    /// Read a file and describe it. Images are decoded, so this had better not run on the
+    /// event loop.
+    pub fn read(path: &Path) -> Result<Self, String> {
+        let data =
+            fs::read(path).map_err(|e| format!("Unable to read {}: {}", path.display(), e))?;
+        let name = path
+            .file_name()
+            .map_or_else(|| "file".to_string(), |s| s.to_string_lossy().to_string());
+        let mimetype = mimetype(path, &data);
+        let mut file = Self {
+            name,
+            kind: attachment_kind(&mimetype),
+            mimetype,
+            size: data.len(),
+            data,
+            dimensions: None,
+            thumbnail: None,
+        };
+        file.describe_image();
+        Ok(file)
+    }
+
+    /// Compute the dimensions and thumbnail of the images we can decode.
+    fn describe_image(&mut self) {
+        let (format, thumbnail_format, thumbnail_mimetype) = match self.mimetype.as_str() {
+            "image/png" => (ImageFormat::Png, ImageOutputFormat::Png, "image/png"),
+            "image/gif" => (ImageFormat::Gif, ImageOutputFormat::Png, "image/png"),
+            "image/jpeg" => (ImageFormat::Jpeg, ImageOutputFormat::Jpeg(85), "image/jpeg"),
+            _ => return,
+        };
+        let image = match image::load_from_memory_with_format(&self.data, format) {
+            Ok(image) => image,
+            Err(_) => return,
+        };
+        let (width, height) = image.dimensions();
+        self.dimensions = Some((width, height));
+
+        // Small images are their own thumbnail
+        if width <= THUMBNAIL_WIDTH && height <= THUMBNAIL_HEIGHT {
+            return;
+        }
+        let thumbnail = image.thumbnail(THUMBNAIL_WIDTH, THUMBNAIL_HEIGHT);
+        let mut data = vec![];
+        if thumbnail.write_to(&mut data, thumbnail_format).is_err() || data.len() >= self.data.len()
+        {
+            return;
+        }
+        self.thumbnail = Some(Thumbnail {
+            size: data.len(),
+            data,
+            mimetype: thumbnail_mimetype.to_string(),
+            width: thumbnail.width(),
+            height: thumbnail.height(),
+        });
+    }
+}
+
+/// MIME type of a file, from its first bytes, or else from its extension.
+pub fn mimetype(path: &Path, data: &[u8]) -> String {
+    if data.len() >= 12 && &data[4..8] == b"ftyp" {
+        let brand = BRANDS.iter().find(|(brand, _)| *brand == &data[8..12]);
+        return match brand {
+            Some((_, mimetype)) => mimetype.to_string(),
+            None => extension_mimetype(path)
+                .unwrap_or(ISO_MEDIA_MIMETYPE)
+                .to_string(),
+        };
+    }
+    let signature = SIGNATURES.iter().find(|(signature, _)| {
+        data.len() >= signature.len()
+            && signature
+                .iter()
+                .zip(data.iter())
+                .all(|(s, d)| *s == b'?' || s == d)
+    });
+    if let Some((_, mimetype)) = signature {
+        return mimetype.to_string();
+    }
+    extension_mimetype(path)
+        .unwrap_or(DEFAULT_MIMETYPE)
+        .to_string()
+}
+
+fn extension_mimetype(path: &Path) -> Option<&'static str> {
+    let extension = path
+        .extension()
+        .map(|e| e.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    EXTENSIONS
+        .iter()
+        .find(|(e, _)| *e == extension)
+        .map(|(_, mimetype)| *mimetype)
+}
+
+pub fn attachment_kind(mimetype: &str) -> AttachmentKind {
+    match mimetype.split('/').next() {
+        Some("image") => AttachmentKind::Image,
+        Some("audio") => AttachmentKind::Audio,
+        Some("video") => AttachmentKind::Video,
+        _ => AttachmentKind::File,
+    }
+}
+
+// =============================================================================
+// Media repository
+// =============================================================================
+/// URL of an endpoint of the media repository, below the path of the homeserver URL.
+fn media_url(homeserver: &url::Url, endpoint: &[&str]) -> Result<url::Url, ApiError> {
+    let mut url = homeserver.clone();
+    url.path_segments_mut()
+        .map_err(|_| ApiError::other(format!("Bad homeserver URL '{}'", homeserver)))?
+        .pop_if_empty()
+        .extend(["_matrix", "media", "r0"].iter().chain(endpoint));
+    Ok(url)
+}
+
+/// Upload a file to the media repository of the homeserver, returning its content URI.
+///
+/// The percentage of the file sent so far goes to `progress` each time it changes.
+pub async fn upload(
+    homeserver: &url::Url,
+    access_token: &str,
+    name: &str,
+    mimetype: &str,
+    data: Vec<u8>,
+    progress: mpsc::UnboundedSender<u8>,
+) -> Result<String, ApiError> {
+    let mut url = media_url(homeserver, &["upload"])?;
+    url.query_pairs_mut().append_pair("filename", name);
+
+    // Chunks share the buffer rather than copying it
+    let data = hyper::body::Bytes::from(data);
+    let total = data.len();
+    let chunks = (0..total)
+        .step_by(UPLOAD_CHUNK)
+        .map(move |start| data.slice(start..total.min(start + UPLOAD_CHUNK)));
+    let mut sent = 0;
+    let mut percent = 0;
+    let body = futures_util::stream::iter(chunks.map(move |chunk| {
+        sent += chunk.len();
+        let new_percent = (sent * 100 / total) as u8;
+        if new_percent != percent {
+            percent = new_percent;
+            let _ = progress.send(percent);
+        }
+        Ok::<_, std::io::Error>(chunk)
+    }));
+
+    let request = hyper::Request::post(url.as_str())
+        .header("Authorization", format!("Bearer {}", access_token))
+        .header("Content-Type", mimetype)
+        .header("Content-Length", total)
+        .body(hyper::Body::wrap_stream(body))
+        .map_err(ApiError::other)?;
+    let client = hyper::Client::builder().build::<_, hyper::Body>(hyper_tls::HttpsConnector::new());
+    let response = client.request(request).await.map_err(ApiError::other)?;
+    let status = response.status();
+    let body = hyper::body::to_bytes(response.into_body())
+        .await
+        .map_err(ApiError::other)?;
+    if !status.is_success() {
+        return Err(ApiError::from_response(status.as_u16(), &body));
+    }
+    let body: serde_json::Value = serde_json::from_slice(&body).map_err(ApiError::other)?;
+    match body["content_uri"].as_str() {
+        Some(uri) => Ok(uri.to_string()),
+        None => Err(ApiError::other("No content URI in the upload response")),
+    }
+}
+
+/// Download a file of the media repository of the homeserver to `path`.
+///
+/// The file is written next to `path` first, so that `path` only ever holds complete files.
+pub async fn download(
+    homeserver: &url::Url,
+    access_token: &str,
+    (server, media_id): (&str, &str),
+    path: &Path,
+    progress: mpsc::UnboundedSender<u8>,
+) -> Result<(), ApiError> {
+    let url = media_url(homeserver, &["download", server, media_id])?;
+
+    let request = hyper::Request::get(url.as_str())
+        .header("Authorization", format!("Bearer {}", access_token))
+        .body(hyper::Body::empty())
+        .map_err(ApiError::other)?;
+    let client = hyper::Client::builder().build::<_, hyper::Body>(hyper_tls::HttpsConnector::new());
+    let response = client.request(request).await.map_err(ApiError::other)?;
+    let status = response.status();
+    if !status.is_success() {
+        let body = hyper::body::to_bytes(response.into_body())
+            .await
+            .map_err(ApiError::other)?;
+        return Err(ApiError::from_response(status.as_u16(), &body));
+    }
+    let total = response
+        .headers()
+        .get("Content-Length")
+        .and_then(|length| length.to_str().ok())
+        .and_then(|length| length.parse::<usize>().ok());
+
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".part");
+    let res = match write_body(response.into_body(), Path::new(&partial), total, progress).await {
+        Ok(()) => tokio::fs::rename(&partial, path)
+            .await
+            .map_err(ApiError::other),
+        Err(e) => Err(e),
+    };
+    // Leave no partial file behind
+    if res.is_err() {
+        let _ = tokio::fs::remove_file(&partial).await;
+    }
+    res
+}
+
+/// Write a response body of `total` bytes, if known, to `path`.
+async fn write_body(
+    mut body: hyper::Body,
+    path: &Path,
+    total: Option<usize>,
+    progress: mpsc::UnboundedSender<u8>,
+) -> Result<(), ApiError> {
+    let mut file = tokio::fs::File::create(path)
+        .await
+        .map_err(ApiError::other)?;
+    let mut received = 0;
+    let mut percent = 0;
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk.map_err(ApiError::other)?;
+        file.write_all(&chunk).await.map_err(ApiError::other)?;
+        received += chunk.len();
+        if let Some(total) = total.filter(|total| *total > 0) {
+            let new_percent = (received * 100 / total).min(100) as u8;
+            if new_percent != percent {
+                percent = new_percent;
+                let _ = progress.send(percent);
+            }
+        }
+    }
+    match total {
+        Some(total) if received < total => Err(ApiError::other(format!(
+            "Connection closed after {} of {} bytes",
+            received, total
+        ))),
+        _ => file.flush().await.map_err(ApiError::other),
+    }
+}
+
+// =============================================================================
+// Downloaded files
+// =============================================================================
+/// Server name and media ID of a content URI (`mxc://<server>/<media ID>`).
+pub fn parse_mxc(url: &str) -> Option<(&str, &str)> {
+    let mut parts = url.strip_prefix("mxc://")?.splitn(2, '/');
+    let server = parts.next().filter(|server| !server.is_empty())?;
+    let media_id = parts
+        .next()
+        .filter(|id| !id.is_empty() && !id.contains('/'))?;
+    Some((server, media_id))
+}
+
+/// Name safe to use as a file name, whatever the server or the other users sent.
+fn safe_file_name(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | '\0' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    match name.trim_start_matches('.') {
+        "" => "attachment".to_string(),
+        name => name.to_string(),
+    }
+}
+
+/// Where the media cache keeps a file of the media repository.
+pub fn cache_path(cache_dir: &Path, (server, media_id): (&str, &str)) -> PathBuf {
+    cache_dir
+        .join("media")
+        .join(safe_file_name(server))
+        .join(safe_file_name(media_id))
+}
+
+/// Copy a cached file to `dir` under `name`, numbered not to overwrite any other file.
+pub fn save(cached: &Path, dir: &Path, name: &str) -> Result<PathBuf, String> {
+    fs::create_dir_all(dir).map_err(|e| format!("Unable to create {}: {}", dir.display(), e))?;
+    let name = safe_file_name(name);
+    let (stem, extension) = match name.rfind('.') {
+        Some(i) if i > 0 => (&name[..i], &name[i..]),
+        _ => (name.as_str(), ""),
+    };
+    let mut path = dir.join(&name);
+    let mut n = 1;
+    while path.exists() {
+        path = dir.join(format!("{} ({}){}", stem, n, extension));
+        n += 1;
+    }
+    if let Err(e) = fs::copy(cached, &path) {
+        let _ = fs::remove_file(&path);
+        return Err(format!("Unable to write {}: {}", path.display(), e));
+    }
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{attachment_kind, media_url, mimetype};
+    use crate::event::AttachmentKind;
+    use std::path::Path;
+
+    /// Start of an ISO base media file of major brand `brand`.
+    fn ftyp(brand: &[u8]) -> Vec<u8> {
+        let mut data = b"\0\0\0\x20ftyp".to_vec();
+        data.extend_from_slice(brand);
+        data.extend_from_slice(b"\0\0\0\0");
+        data
+    }
+
+    #[test]
+    fn signatures() {
+        let png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+        assert_eq!(mimetype(Path::new("a.txt"), png), "image/png");
+        assert_eq!(mimetype(Path::new("a"), b"\xff\xd8\xff\xe0"), "image/jpeg");
+        assert_eq!(
+            mimetype(Path::new("a"), b"RIFF\0\0\0\0WEBPVP8 "),
+            "image/webp"
+        );
+        assert_eq!(
+            mimetype(Path::new("a"), b"RIFF\0\0\0\0WAVEfmt "),
+            "audio/wav"
+        );
+        assert_eq!(mimetype(Path::new("a"), b"%PDF-1.7"), "application/pdf");
+    }
+
+    #[test]
+    fn iso_media_brands() {
+        let name = Path::new("a");
+        assert_eq!(mimetype(name, &ftyp(b"M4A ")), "audio/mp4");
+        assert_eq!(mimetype(name, &ftyp(b"qt  ")), "video/quicktime");
+        assert_eq!(mimetype(name, &ftyp(b"heic")), "image/heic");
+        assert_eq!(mimetype(name, &ftyp(b"avif")), "image/avif");
+        assert_eq!(mimetype(name, &ftyp(b"isom")), "video/mp4");
+        // Unknown brands defer to the extension
+        assert_eq!(mimetype(Path::new("a.m4a"), &ftyp(b"XYZ ")), "audio/mp4");
+        assert_eq!(
+            mimetype(Path::new("a.MOV"), &ftyp(b"XYZ ")),
+            "video/quicktime"
+        );
+    }
+
+    #[test]
+    fn extensions() {
+        assert_eq!(mimetype(Path::new("notes.TXT"), b"hello"), "text/plain");
+        assert_eq!(mimetype(Path::new("song.mp3"), b""), "audio/mpeg");
+        assert_eq!(
+            mimetype(Path::new("data.bin"), b"\0\0"),
+            "application/octet-stream"
+        );
+        assert_eq!(
+            mimetype(Path::new("noext"), b"text"),
+            "application/octet-stream"
+        );
+        // Short files are not mistaken for ISO base media files
+        assert_eq!(mimetype(Path::new("a.txt"), b"\0\0\0\0ftyp"), "text/plain");
+    }
+
+    #[test]
+    fn media_urls() {
+        let url = |homeserver: &str, endpoint: &[&str]| {
+            let homeserver = url::Url::parse(homeserver).unwrap();
+            media_url(&homeserver, endpoint).unwrap().to_string()
+        };
+        assert_eq!(
+            url("https://example.org", &["upload"]),
+            "https://example.org/_matrix/media/r0/upload"
+        );
+        assert_eq!(
+            url("https://example.org/matrix", &["upload"]),
+            "https://example.org/matrix/_matrix/media/r0/upload"
+        );
+        assert_eq!(
+            url(
+                "https://example.org:8448/matrix/",
+                &["download", "a.org", "x/y"]
+            ),
+            "https://example.org:8448/matrix/_matrix/media/r0/download/a.org/x%2Fy"
+        );
+    }
+
+    #[test]
+    fn attachment_kinds() {
+        assert_eq!(attachment_kind("image/heic"), AttachmentKind::Image);
+        assert_eq!(attachment_kind("audio/mp4"), AttachmentKind::Audio);
+        assert_eq!(attachment_kind("video/quicktime"), AttachmentKind::Video);
+        assert_eq!(attachment_kind("application/pdf"), AttachmentKind::File);
+        assert_eq!(attachment_kind("text/plain"), AttachmentKind::File);
+        assert_eq!(attachment_kind(""), AttachmentKind::File);
+    }
+}
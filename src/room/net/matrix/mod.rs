@@ -0,0 +1,2053 @@
+use crate::dirs;
+use crate::event::{
+    self, Delivery, NetEventKind, NewRoom, Presence, Source, StateChange, STATE_DIRECT,
+};
+use crate::net_matrix_dbg as dbg;
+use crate::room;
+use crate::room::net::{Publish, Relation};
+use crate::sequence_number::SequenceNumber;
+use futures_util::future::{self, Either};
+use js_int::UInt;
+use ruma_client::{api::r0, Session};
+use ruma_client_api::r0::{
+    context::get_context,
+    membership::get_member_events,
+    message::get_message_events::{self, Direction},
+    presence::set_presence,
+    read_marker::set_read_marker,
+    redact::redact_event,
+    typing::create_typing_event,
+};
+use ruma_events::collections::{
+    all::{RoomEvent, StateEvent},
+    only,
+};
+pub use ruma_events::presence::PresenceState as MatrixPresence;
+use ruma_events::room::{
+    member::{MemberEventContent, MembershipState},
+    power_levels::PowerLevelsEventContent,
+};
+use ruma_events::stripped::StrippedState;
+use ruma_events::EventResult;
+use ruma_identifiers::{EventId, RoomId as MatrixRoomId, RoomIdOrAliasId, UserId};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::sync::Mutex;
+
+mod api;
+mod commands;
+mod compose;
+mod connection;
+mod error;
+mod media;
+mod queue;
+mod render;
+mod rooms;
+mod session;
+mod sync;
+mod transfer;
+mod uri;
+
+use api::sync_events::IncomingResponse;
+use commands::{Command, CreateRoom};
+use connection::{Backoff, State};
+use error::{ApiError, ConnectError};
+use queue::OutgoingQueue;
+use rooms::{Member, Room};
+use session::SessionStore;
+use sync::SyncResult;
+use uri::RoomTarget;
+
+// Number of events fetched per history request
+const HISTORY_PAGE: u32 = 50;
+// Maximum number of history requests made to fill a timeline gap
+const GAP_MAX_PAGES: usize = 10;
+// Milliseconds our typing notifications last, longer than the input renews them
+const TYPING_TIMEOUT: u32 = 15_000;
+
+// Account data listing the direct chats
+const DIRECT_EVENT_TYPE: &str = "m.direct";
+// Encryption algorithm of the rooms we create
+const ENCRYPTION_ALGORITHM: &str = "m.megolm.v1.aes-sha2";
+
+type Client = ruma_client::Client<hyper_tls::HttpsConnector<hyper::client::HttpConnector>>;
+
+fn encryption_state() -> serde_json::Value {
+    serde_json::json!({
+        "type": "m.room.encryption",
+        "state_key": "",
+        "content": { "algorithm": ENCRYPTION_ALGORITHM },
+    })
+}
+
+/// Request for a page of the events preceding `from`, newest first.
+fn messages_request(
+    room_id: MatrixRoomId,
+    from: String,
+    to: Option<String>,
+) -> get_message_events::Request {
+    get_message_events::Request {
+        room_id,
+        from,
+        to,
+        dir: Direction::Backward,
+        limit: Some(HISTORY_PAGE.into()),
+        filter: None,
+    }
+}
+
+// =============================================================================
+// Server
+// =============================================================================
+struct Error {
+    id: room::Id,
+    error: String,
+}
+
+struct ErrorBatch {
+    errors: Vec<Error>,
+}
+
+impl<S> From<(room::Id, S)> for ErrorBatch
+where
+    S: AsRef<str>,
+{
+    fn from((id, s): (room::Id, S)) -> Self {
+        ErrorBatch {
+            errors: vec![Error {
+                id,
+                error: s.as_ref().to_string(),
+            }],
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+pub struct Conf {
+    pub url: url::Url,
+    pub credentials: Option<Credentials>,
+    // Server side long polling timeout of the sync requests (ms)
+    pub sync_timeout: u32,
+}
+
+enum Stimulus {
+    Action(room::net::Action),
+    Sync(SyncResult),
+    Retry,
+    // Outcome of the media transfers of a room
+    Upload(room::Id, transfer::UploadResult),
+    Download(room::Id, transfer::DownloadResult),
+    // Full member list of a room
+    Members(room::Id, MembersResult),
+    // Page of the events skipped by the server between two syncs
+    Gap(room::Id, GapResult),
+}
+
+type MembersResult = Result<get_member_events::IncomingResponse, ApiError>;
+type GapResult = Result<Vec<EventResult<RoomEvent>>, String>;
+
+pub struct Server {
+    id: room::Id,
+    // Connection parameters
+    conf: Conf,
+
+    // Current connection state
+    state: State,
+    backoff: Backoff,
+    last_sync: Option<String>,
+    client: Option<Client>,
+    session: Option<Session>,
+
+    // Thread handles
+    sync_thread_stop: Option<mpsc::Sender<()>>,
+    retry_thread_stop: Option<mpsc::Sender<()>>,
+    io_thread_stop: Option<mpsc::Sender<()>>,
+
+    // Server room
+    input: mpsc::Sender<room::Event>,
+    request: mpsc::Receiver<room::net::Action>,
+    request_sender: mpsc::Sender<room::net::Action>,
+
+    // Sync loop results, retry timer ticks and outcomes of the requests made in tasks
+    internal: mpsc::Receiver<Stimulus>,
+    internal_sender: mpsc::Sender<Stimulus>,
+
+    // Rooms data
+    rooms_by_name: HashMap<MatrixRoomId, usize>,
+    rooms: HashMap<usize, Room>,
+    room_sn: Arc<Mutex<SequenceNumber>>,
+    msg_sn: SequenceNumber,
+    // Prefix making our transaction IDs unique across runs
+    txn_prefix: String,
+    // Messages not sent yet
+    queue: OutgoingQueue,
+    // User of each direct chat, by Matrix room ID
+    direct: HashMap<String, String>,
+
+    // Our presence, and the status message shown with it
+    presence: MatrixPresence,
+    status_msg: Option<String>,
+
+    // Directory the attachments are saved to, and command they are opened with
+    downloads: Option<PathBuf>,
+    opener: Option<Vec<String>>,
+    // Set once the server room is closed, to stop the actor
+    closed: bool,
+}
+
+impl Server {
+    pub fn new(
+        id: room::Id,
+        conf: Conf,
+        handle: room::ServerHandle,
+        self_sender: mpsc::Sender<room::net::Action>,
+        room_sn: Arc<Mutex<SequenceNumber>>,
+    ) -> Result<Self, String> {
+        let (internal_sender, internal) = mpsc::channel(1);
+        let queue = OutgoingQueue::open(
+            &conf.url,
+            conf.credentials.as_ref().map(|c| c.username.as_str()),
+        );
+        Ok(Self {
+            id,
+            conf,
+
+            state: State::LoggedOut,
+            backoff: Backoff::default(),
+            last_sync: None,
+            client: None,
+            session: None,
+
+            sync_thread_stop: None,
+            retry_thread_stop: None,
+            io_thread_stop: None,
+
+            input: handle.input,
+            request: handle.request,
+            request_sender: self_sender,
+
+            internal,
+            internal_sender,
+
+            rooms_by_name: HashMap::new(),
+            rooms: HashMap::new(),
+            room_sn,
+            msg_sn: SequenceNumber::default(),
+            txn_prefix: format!(
+                "{}.{}",
+                chrono::Utc::now().timestamp_millis(),
+                rand::random::<u32>()
+            ),
+            queue,
+            direct: HashMap::new(),
+            presence: MatrixPresence::Online,
+            status_msg: None,
+            downloads: dirs::download_dir(),
+            opener: None,
+            closed: false,
+        })
+    }
+
+    async fn add_room_name(&mut self, name: &MatrixRoomId) -> Result<room::Id, String> {
+        if self.rooms_by_name.get(&name).is_some() {
+            return Err(format!("Room '{}' is already opened", name));
+        }
+        let sn = self.room_sn.lock().await.next().unwrap();
+        self.rooms_by_name.insert(name.clone(), sn);
+        self.rooms.insert(sn, Room::new(name.clone()));
+        Ok(sn)
+    }
+
+    async fn send_as(
+        &mut self,
+        id: room::Id,
+        date: usize,
+        source: Option<Source>,
+        event: NetEventKind,
+    ) {
+        self.input
+            .send(event.to_event(id, date, source))
+            .await
+            .unwrap();
+    }
+
+    async fn send_current(&mut self, event: NetEventKind) {
+        self.input
+            .send(event.to_current_event(self.id.clone(), None))
+            .await
+            .unwrap();
+    }
+
+    async fn send_current_by(&mut self, source: Source, event: NetEventKind) {
+        self.input
+            .send(event.to_current_event(self.id.clone(), Some(source)))
+            .await
+            .unwrap();
+    }
+
+    async fn send_current_as(&mut self, id: room::Id, event: NetEventKind) {
+        self.input
+            .send(event.to_current_event(id, None))
+            .await
+            .unwrap();
+    }
+
+    async fn send_current_as_by(&mut self, id: room::Id, source: Source, event: NetEventKind) {
+        self.input
+            .send(event.to_current_event(id, Some(source)))
+            .await
+            .unwrap();
+    }
+
+    async fn send_error(&mut self, error: &str) {
+        self.send_current(NetEventKind::Error(error.to_string()))
+            .await
+    }
+
+    async fn send_error_as(&mut self, id: usize, error: &str) {
+        self.send_current_as(id, NetEventKind::Error(error.to_string()))
+            .await
+    }
+
+    async fn spawn_room(
+        &mut self,
+        name: &MatrixRoomId,
+        alias: Option<String>,
+    ) -> Result<(), String> {
+        let id = self.add_room_name(name).await?;
+
+        self.send_current(NetEventKind::NewRoom(NewRoom {
+            id: Some(id),
+            alias: alias.unwrap_or_else(|| name.to_string()),
+            requester: self.request_sender.clone(),
+        }))
+        .await;
+        self.echo_queue(id).await;
+        Ok(())
+    }
+
+    /// Forget about a room and have the UI remove it.
+    async fn close_room(&mut self, id: room::Id) {
+        if self.remove_room(id).is_some() {
+            self.send_current_as(id, NetEventKind::Closed).await;
+        }
+    }
+
+    fn remove_room(&mut self, id: usize) -> Option<MatrixRoomId> {
+        match self.rooms.remove(&id) {
+            Some(room) => {
+                self.rooms_by_name.remove(&room.matrix_id);
+                Some(room.matrix_id)
+            }
+            None => None,
+        }
+    }
+
+    /// Resolve a room designated by the user, open it and join it.
+    async fn open_room(&mut self, target: RoomTarget, alias: String) -> Result<(), ErrorBatch> {
+        let RoomTarget { room, mut via } = target;
+        let room_id = match room {
+            RoomIdOrAliasId::RoomId(room_id) => room_id,
+            RoomIdOrAliasId::RoomAliasId(room_alias) => {
+                let client = match self.client.as_ref() {
+                    Some(client) => client,
+                    None => return Err(ErrorBatch::from((self.id, "Not connected to the server"))),
+                };
+                let resp = client
+                    .request(r0::alias::get_alias::Request {
+                        room_alias: room_alias.clone(),
+                    })
+                    .await
+                    .map_err(|e| {
+                        let error =
+                            format!("Failed to resolve '{}': {}", room_alias, ApiError::from(e));
+                        ErrorBatch::from((self.id, error))
+                    })?;
+                // Explicit routing hints take precedence over the directory ones
+                if via.is_empty() {
+                    via = resp.servers;
+                }
+                resp.room_id
+            }
+        };
+
+        self.spawn_room(&room_id, Some(alias))
+            .await
+            .map_err(|e| ErrorBatch::from((self.id, e)))?;
+        let id = self.rooms_by_name[&room_id];
+        self.rooms.get_mut(&id).unwrap().via = via;
+        self.join(id).await;
+        Ok(())
+    }
+
+    async fn leave(&mut self, id: room::Id) -> Result<(), ErrorBatch> {
+        let room_name = self.rooms[&id].matrix_id.clone();
+        let client = self
+            .client
+            .as_ref()
+            .ok_or_else(|| ErrorBatch::from((id, "Not connected to the server")))?;
+        client
+            .request(r0::membership::leave_room::Request {
+                room_id: room_name.clone(),
+            })
+            .await
+            .map(|_| ())
+            .map_err(|e| {
+                let error = format!(
+                    "Failed to leave room '{}': '{}'",
+                    room_name,
+                    ApiError::from(e)
+                );
+                ErrorBatch::from((id, error))
+            })
+    }
+
+    /// Remove a left room from the room history of the account.
+    async fn forget(&mut self, id: room::Id) -> Result<(), ErrorBatch> {
+        let room_name = self.rooms[&id].matrix_id.clone();
+        let client = self
+            .client
+            .as_ref()
+            .ok_or_else(|| ErrorBatch::from((id, "Not connected to the server")))?;
+        client
+            .request(r0::membership::forget_room::Request {
+                room_id: room_name.clone(),
+            })
+            .await
+            .map(|_| ())
+            .map_err(|e| {
+                let error = format!(
+                    "Failed to forget room '{}': '{}'",
+                    room_name,
+                    ApiError::from(e)
+                );
+                ErrorBatch::from((id, error))
+            })
+    }
+
+    async fn join(&mut self, id: room::Id) {
+        let room = &self.rooms[&id];
+        let room_name = room.matrix_id.clone();
+        let request = api::join_room_by_id_or_alias::Request {
+            room_id_or_alias: RoomIdOrAliasId::RoomId(room_name.clone()),
+            server_name: room
+                .via
+                .iter()
+                .map(|server| ("server_name".to_string(), server.clone()))
+                .collect(),
+        };
+        let event = match self.client.as_ref() {
+            Some(client) => match client.request(request).await {
+                Ok(_) => {
+                    if let Some(room) = self.rooms.get_mut(&id) {
+                        room.joined = true;
+                    }
+                    NetEventKind::Connected
+                }
+                Err(e) => NetEventKind::Error(format!(
+                    "Failed to join room '{}': '{}'",
+                    room_name,
+                    ApiError::from(e)
+                )),
+            },
+            None => NetEventKind::Error("Not connected to the server".to_string()),
+        };
+        self.send_current_as(id, event).await;
+    }
+
+    /// Open a session on the server, resuming the saved one of the account when the server still
+    /// accepts it.
+    async fn connect(&mut self) -> Result<Session, ConnectError> {
+        let url = self.conf.url.clone();
+        let credentials = match &self.conf.credentials {
+            Some(c) => c,
+            None => {
+                let client = ruma_client::Client::https(url, None);
+                let session = client.register_guest().await.map_err(ApiError::from)?;
+                self.client = Some(client);
+                return Ok(session);
+            }
+        };
+        let username = credentials.username.clone();
+        let password = credentials.password.clone();
+
+        // Try to resume the previous session
+        let store = SessionStore::new(&url, &username);
+        let saved = store.as_ref().and_then(|s| s.load());
+        let device_id = saved.as_ref().map(|s| s.device_id.clone());
+        if let Some(session) = saved {
+            dbg!("resuming session of {}", session.user_id);
+            let client = ruma_client::Client::https(url.clone(), Some(session.clone()));
+            match client.request(r0::account::whoami::Request {}).await {
+                Ok(_) => {
+                    self.client = Some(client);
+                    return Ok(session);
+                }
+                Err(e) => {
+                    let e = ApiError::from(e);
+                    if !e.is_unknown_token() {
+                        return Err(e.into());
+                    }
+                    if let Some(store) = store.as_ref() {
+                        store.remove();
+                    }
+                    self.send_error(&format!("Saved session rejected by the server: {}", e))
+                        .await;
+                }
+            }
+        }
+
+        // Fall back to a password login
+        if password.is_empty() {
+            return Err(ConnectError::Fatal(format!(
+                "No valid saved session for '{}', a password is required",
+                username
+            )));
+        }
+        let client = ruma_client::Client::https(url, None);
+        let session = client
+            .log_in(
+                username,
+                password,
+                device_id,
+                Some("rust_matrix_client".to_string()),
+            )
+            .await
+            .map_err(ApiError::from)?;
+        if let Some(store) = store {
+            if let Err(e) = store.save(&session) {
+                self.send_error(&format!("Unable to save the session: {}", e))
+                    .await;
+            }
+        }
+        self.client = Some(client);
+        Ok(session)
+    }
+
+    fn start_sync(&mut self) {
+        let client = match self.client.as_ref() {
+            Some(client) => client.clone(),
+            None => return,
+        };
+
+        // Stop previous sync loop
+        self.sync_thread_stop.take();
+
+        // Build new sync loop handle
+        let (tx, rx) = mpsc::channel(1);
+        self.sync_thread_stop = Some(tx);
+
+        sync::spawn(
+            client,
+            sync::Conf {
+                since: self.last_sync.clone(),
+                timeout: self.conf.sync_timeout,
+                presence: self.presence,
+            },
+            self.internal_sender.clone(),
+            rx,
+        );
+    }
+
+    async fn set_state(&mut self, state: State) {
+        let previous = self.state;
+        self.state = state;
+        dbg!("connection state: {} -> {}", previous, state);
+        if state == State::Connected && previous != State::Connected {
+            self.backoff.reset();
+            self.send_current(NetEventKind::Connected).await;
+        } else if state != State::Connected && previous == State::Connected {
+            self.send_current(NetEventKind::Disconnected).await;
+        }
+    }
+
+    fn schedule_retry(&mut self, retry_after: Option<Duration>) -> Duration {
+        let delay = self.backoff.next_delay(retry_after);
+
+        // Replace any pending retry
+        let (tx, mut rx) = mpsc::channel::<()>(1);
+        self.retry_thread_stop = Some(tx);
+
+        let mut sender = self.internal_sender.clone();
+        tokio::spawn(async move {
+            let timer = tokio::time::delay_for(delay);
+            let stopped = rx.recv();
+            futures_util::pin_mut!(stopped);
+            if let Either::Left(_) = future::select(timer, stopped).await {
+                let _ = sender.send(Stimulus::Retry).await;
+            }
+        });
+        delay
+    }
+
+    async fn back_off(&mut self, e: ApiError) {
+        // Only the first failure of a streak goes to the timeline
+        let first_failure = self.backoff.attempts() == 0;
+        let delay = self.schedule_retry(e.retry_after());
+        self.set_state(State::BackingOff).await;
+        if first_failure {
+            let error = format!(
+                "Connection to server '{}' failed: '{}'. Retrying in {}s, then in the background",
+                self.conf.url,
+                e,
+                delay.as_secs()
+            );
+            self.send_error(&error).await;
+        } else {
+            dbg!("connection failed again: {}, retrying in {:?}", e, delay);
+        }
+    }
+
+    /// Open a session and start syncing, retrying later on transient failures.
+    async fn open(&mut self) {
+        self.set_state(State::Connecting).await;
+        match self.connect().await {
+            Ok(s) => {
+                dbg!("Starting sync thread");
+                self.session = Some(s);
+                self.start_sync();
+            }
+            Err(ConnectError::Transient(e)) => self.back_off(e).await,
+            Err(ConnectError::Fatal(e)) => {
+                self.set_state(State::LoggedOut).await;
+                let error = format!("Unable to connect to server '{}': '{}'", self.conf.url, e);
+                self.send_error(&error).await;
+            }
+        }
+    }
+
+    async fn close(&mut self) {
+        self.retry_thread_stop.take();
+        self.sync_thread_stop.take();
+        self.client.take();
+        self.session.take();
+        self.backoff.reset();
+        self.set_state(State::LoggedOut).await;
+    }
+
+    async fn retry(&mut self) {
+        if self.state != State::BackingOff {
+            return;
+        }
+        if self.client.is_some() {
+            // The session is still valid, only the sync loop died
+            self.set_state(State::Connecting).await;
+            self.start_sync();
+        } else {
+            self.open().await;
+        }
+    }
+
+    async fn process_server_command(&mut self, line: &str) -> Result<(), ErrorBatch> {
+        dbg!("process_server_command: {}", line);
+        match commands::parse(line).map_err(|e| ErrorBatch::from((self.id, e)))? {
+            Command::Create(options) => self.create_room(options).await,
+            Command::Dm(user_id) => self.open_dm(user_id).await,
+            Command::Presence(presence) => {
+                self.presence = presence;
+                self.set_presence().await?;
+                // Stop the sync loop from overriding our presence, or let it keep us online
+                if self.client.is_some() {
+                    self.start_sync();
+                }
+                Ok(())
+            }
+            Command::Status(status_msg) => {
+                self.status_msg = status_msg;
+                self.set_presence().await
+            }
+            Command::Downloads(dir) => {
+                let content = format!("Attachments are now saved to {}", dir.display());
+                self.downloads = Some(dir);
+                self.send_current(NetEventKind::Info(content)).await;
+                Ok(())
+            }
+            Command::Opener(opener) => {
+                let content = match opener.as_ref() {
+                    Some(opener) => format!("Attachments are now opened with {}", opener.join(" ")),
+                    None => "Attachments are not opened anymore".to_string(),
+                };
+                self.opener = opener;
+                self.send_current(NetEventKind::Info(content)).await;
+                Ok(())
+            }
+        }
+    }
+
+    async fn set_presence(&mut self) -> Result<(), ErrorBatch> {
+        let user_id = match self.session.as_ref() {
+            Some(session) => session.user_id.clone(),
+            None => return Err(ErrorBatch::from((self.id, "Not connected to the server"))),
+        };
+        self.client()?
+            .request(set_presence::Request {
+                presence: self.presence,
+                status_msg: self.status_msg.clone(),
+                user_id,
+            })
+            .await
+            .map_err(|e| {
+                let error = format!("Failed to set the presence: {}", ApiError::from(e));
+                ErrorBatch::from((self.id, error))
+            })?;
+        let content = match self.status_msg.as_ref() {
+            Some(status_msg) => format!("Presence set to {} ({})", self.presence, status_msg),
+            None => format!("Presence set to {}", self.presence),
+        };
+        self.send_current(NetEventKind::Info(content)).await;
+        Ok(())
+    }
+
+    fn client(&self) -> Result<&Client, ErrorBatch> {
+        self.client
+            .as_ref()
+            .ok_or_else(|| ErrorBatch::from((self.id, "Not connected to the server")))
+    }
+
+    async fn create_room(&mut self, options: CreateRoom) -> Result<(), ErrorBatch> {
+        let CreateRoom {
+            name,
+            topic,
+            alias,
+            public,
+            invite,
+            encrypted,
+        } = options;
+        let (preset, visibility) = if public {
+            (
+                api::create_room::RoomPreset::PublicChat,
+                api::create_room::Visibility::Public,
+            )
+        } else {
+            (
+                api::create_room::RoomPreset::PrivateChat,
+                api::create_room::Visibility::Private,
+            )
+        };
+        let initial_state = if encrypted {
+            vec![encryption_state()]
+        } else {
+            vec![]
+        };
+        let resp = self
+            .client()?
+            .request(api::create_room::Request {
+                initial_state,
+                invite,
+                is_direct: false,
+                name: name.clone(),
+                preset: Some(preset),
+                room_alias_name: alias,
+                topic,
+                visibility: Some(visibility),
+            })
+            .await
+            .map_err(|e| {
+                let error = format!("Failed to create the room: {}", ApiError::from(e));
+                ErrorBatch::from((self.id, error))
+            })?;
+        self.spawn_created_room(&resp.room_id, name).await;
+        Ok(())
+    }
+
+    /// Open the direct chat with a user, creating it when there is none yet.
+    async fn open_dm(&mut self, user_id: UserId) -> Result<(), ErrorBatch> {
+        let own_id = match self.session.as_ref() {
+            Some(session) => session.user_id.clone(),
+            None => return Err(ErrorBatch::from((self.id, "Not connected to the server"))),
+        };
+        let mut direct = self.direct_rooms(&own_id).await?;
+        self.direct = render::direct_rooms(&direct);
+        let existing: Vec<MatrixRoomId> =
+            direct[user_id.to_string()]
+                .as_array()
+                .map_or(vec![], |rooms| {
+                    rooms
+                        .iter()
+                        .filter_map(|room| room.as_str())
+                        .filter_map(|room| MatrixRoomId::try_from(room).ok())
+                        .collect()
+                });
+        if let Some(room_id) = existing.iter().find(|r| self.rooms_by_name.contains_key(r)) {
+            let id = self.rooms_by_name[room_id];
+            let content = format!("Direct chat with {} already opened", user_id);
+            self.send_current_as(id, NetEventKind::Info(content)).await;
+            return Ok(());
+        }
+        if let Some(room_id) = existing.into_iter().next() {
+            let target = RoomTarget {
+                room: RoomIdOrAliasId::RoomId(room_id),
+                via: vec![],
+            };
+            return self.open_room(target, user_id.to_string()).await;
+        }
+
+        let resp = self
+            .client()?
+            .request(api::create_room::Request {
+                initial_state: vec![],
+                invite: vec![user_id.clone()],
+                is_direct: true,
+                name: None,
+                preset: Some(api::create_room::RoomPreset::TrustedPrivateChat),
+                room_alias_name: None,
+                topic: None,
+                visibility: None,
+            })
+            .await
+            .map_err(|e| {
+                let error = format!("Failed to create the direct chat: {}", ApiError::from(e));
+                ErrorBatch::from((self.id, error))
+            })?;
+
+        // Let other clients know about the new direct chat
+        let room_id = serde_json::Value::String(resp.room_id.to_string());
+        match direct[user_id.to_string()].as_array_mut() {
+            Some(rooms) => rooms.push(room_id),
+            None => direct[user_id.to_string()] = serde_json::Value::Array(vec![room_id]),
+        }
+        let res = self
+            .client()?
+            .request(r0::config::set_global_account_data::Request {
+                data: direct,
+                event_type: DIRECT_EVENT_TYPE.to_string(),
+                user_id: own_id,
+            })
+            .await;
+        if let Err(e) = res {
+            let error = format!("Failed to save the direct chat: {}", ApiError::from(e));
+            self.send_error(&error).await;
+        }
+        self.direct
+            .insert(resp.room_id.to_string(), user_id.to_string());
+
+        self.spawn_created_room(&resp.room_id, Some(user_id.to_string()))
+            .await;
+        Ok(())
+    }
+
+    /// Tell the UI who a direct chat is with.
+    fn set_direct_state(&mut self, id: room::Id) {
+        let room = self.rooms.get_mut(&id).unwrap();
+        let user_id = self.direct.get(&room.matrix_id.to_string()).cloned();
+        room.set_state((STATE_DIRECT, user_id));
+    }
+
+    /// Content of the `m.direct` account data: a map from user IDs to room IDs.
+    async fn direct_rooms(&self, own_id: &UserId) -> Result<serde_json::Value, ErrorBatch> {
+        let res = self
+            .client()?
+            .request(api::get_global_account_data::Request {
+                user_id: own_id.clone(),
+                event_type: DIRECT_EVENT_TYPE.to_string(),
+            })
+            .await;
+        match res.map_err(ApiError::from) {
+            Ok(resp) if resp.data.is_object() => Ok(resp.data),
+            Ok(_) => Ok(serde_json::json!({})),
+            Err(ref e) if e.errcode.as_deref() == Some("M_NOT_FOUND") => Ok(serde_json::json!({})),
+            Err(e) => {
+                let error = format!("Failed to load the direct chats: {}", e);
+                Err(ErrorBatch::from((self.id, error)))
+            }
+        }
+    }
+
+    async fn spawn_created_room(&mut self, room_id: &MatrixRoomId, alias: Option<String>) {
+        // The room may already have come through a sync
+        if !self.rooms_by_name.contains_key(room_id) {
+            self.spawn_room(room_id, alias).await.unwrap();
+        }
+    }
+
+    async fn process_server_action(
+        &mut self,
+        action: room::net::ActionKind,
+    ) -> Result<(), ErrorBatch> {
+        dbg!("process_server_action");
+        match action {
+            room::net::ActionKind::Connect => {
+                dbg!("connect with {:?}", self.conf.credentials);
+                self.retry_thread_stop.take();
+                self.backoff.reset();
+                self.open().await;
+            }
+            room::net::ActionKind::Disconnect => {
+                dbg!("disconnect");
+                self.close().await;
+            }
+            room::net::ActionKind::Close { .. } => {
+                dbg!("close server");
+                let ids: Vec<room::Id> = self.rooms.keys().copied().collect();
+                for id in ids.into_iter() {
+                    self.close_room(id).await;
+                }
+                self.close().await;
+                self.send_current(NetEventKind::Closed).await;
+                self.closed = true;
+            }
+            room::net::ActionKind::Publish(publish) => {
+                dbg!("publish");
+                self.process_server_command(&publish.body).await?
+            }
+            // The server room only holds local events
+            room::net::ActionKind::LoadHistory => {
+                self.send_current(NetEventKind::HistoryLoaded).await
+            }
+            room::net::ActionKind::LoadMembers => (),
+            room::net::ActionKind::LoadEvent(_) => (),
+            room::net::ActionKind::MarkRead(_) => (),
+            room::net::ActionKind::Typing(_) => (),
+            room::net::ActionKind::Retry(_)
+            | room::net::ActionKind::Cancel(_)
+            | room::net::ActionKind::EditQueued { .. } => (),
+            room::net::ActionKind::Redact { .. } => (),
+            room::net::ActionKind::React { .. } => (),
+            room::net::ActionKind::Upload(_) => {
+                return Err(ErrorBatch::from((
+                    self.id,
+                    "Files are shared in the rooms, not in the server room",
+                )))
+            }
+            room::net::ActionKind::Download { .. } => (),
+            room::net::ActionKind::NewRoom(room) => {
+                dbg!("new_room");
+                let room::net::NewRoom { alias, command } = room;
+                let target = match command.first() {
+                    Some(target) => uri::parse(target),
+                    None => Err("Syntax: spawn <alias> <room ID, alias or link>".to_string()),
+                };
+                let target = target.map_err(|e| ErrorBatch::from((self.id, e)))?;
+                self.open_room(target, alias).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn process_sub_room_action(
+        &mut self,
+        action: room::net::Action,
+    ) -> Result<(), ErrorBatch> {
+        dbg!("process_sub_room_action");
+        let room::net::Action { room, action } = action;
+        if !self.rooms.contains_key(&room) {
+            return Err(ErrorBatch::from((
+                self.id,
+                format!("Unknown room {}", room),
+            )));
+        }
+        match action {
+            room::net::ActionKind::Connect => {
+                dbg!("connect");
+                self.join(room).await;
+            }
+            room::net::ActionKind::Disconnect => {
+                dbg!("disconnect");
+                self.leave(room).await?;
+                if let Some(room) = self.rooms.get_mut(&room) {
+                    room.joined = false;
+                }
+                self.send_current_as(room, NetEventKind::Disconnected).await;
+            }
+            room::net::ActionKind::Close { forget } => {
+                dbg!("close");
+                // Close the room even when the server can't be told, the user is done with it
+                let mut res = self.leave(room).await;
+                if forget && res.is_ok() {
+                    res = self.forget(room).await;
+                }
+                self.close_room(room).await;
+                // Report the failures in the server room, as the room is gone
+                if let Err(e) = res {
+                    let id = self.id;
+                    let errors = e.errors.into_iter().map(|e| Error { id, error: e.error });
+                    return Err(ErrorBatch {
+                        errors: errors.collect(),
+                    });
+                }
+            }
+            room::net::ActionKind::Publish(publish) => {
+                dbg!("publish");
+                let txn_id = self.txn_id();
+                self.send_echo(room, &txn_id, &publish, Delivery::Queued)
+                    .await;
+                let matrix_id = self.rooms[&room].matrix_id.to_string();
+                let saved = self.queue.push(&matrix_id, txn_id, publish);
+                self.flush_queue(room).await?;
+                if let Err(e) = saved {
+                    return Err(ErrorBatch::from((
+                        room,
+                        format!("Unable to save the outgoing messages: {}", e),
+                    )));
+                }
+            }
+            room::net::ActionKind::Retry(txn_id) => {
+                dbg!("retry {}", txn_id);
+                let matrix_id = self.rooms[&room].matrix_id.to_string();
+                match self.queue.update(&matrix_id, &txn_id, |q| q.failed = false) {
+                    Ok(true) => (),
+                    Ok(false) => return Err(ErrorBatch::from((room, "No such failed message"))),
+                    Err(e) => return Err(ErrorBatch::from((room, e))),
+                }
+                self.send_delivery(room, &txn_id, Delivery::Queued, None)
+                    .await;
+                self.flush_queue(room).await?
+            }
+            room::net::ActionKind::Cancel(txn_id) => {
+                dbg!("cancel {}", txn_id);
+                let matrix_id = self.rooms[&room].matrix_id.to_string();
+                if let Err(e) = self.queue.remove(&matrix_id, &txn_id) {
+                    return Err(ErrorBatch::from((room, e)));
+                }
+            }
+            room::net::ActionKind::EditQueued { txn_id, publish } => {
+                dbg!("edit queued {}", txn_id);
+                let matrix_id = self.rooms[&room].matrix_id.to_string();
+                let mut edited = None;
+                let res = self.queue.update(&matrix_id, &txn_id, |q| {
+                    // The new content keeps the reply of the message
+                    q.publish.msgtype = publish.msgtype;
+                    q.publish.body = publish.body;
+                    q.publish.formatted_body = publish.formatted_body;
+                    q.failed = false;
+                    edited = Some(q.publish.clone());
+                });
+                match (res, edited) {
+                    (Ok(true), Some(publish)) => {
+                        self.send_echo(room, &txn_id, &publish, Delivery::Queued)
+                            .await;
+                        self.flush_queue(room).await?
+                    }
+                    (Err(e), _) => return Err(ErrorBatch::from((room, e))),
+                    _ => return Err(ErrorBatch::from((room, "This message was already sent"))),
+                }
+            }
+            room::net::ActionKind::LoadHistory => {
+                dbg!("load history");
+                let res = self.load_history(room).await;
+                self.send_current_as(room, NetEventKind::HistoryLoaded)
+                    .await;
+                res?
+            }
+            room::net::ActionKind::LoadMembers => {
+                dbg!("load members");
+                self.load_members(room)?
+            }
+            room::net::ActionKind::LoadEvent(event_id) => {
+                dbg!("load event {}", event_id);
+                self.load_event(room, &event_id).await?
+            }
+            room::net::ActionKind::React { id, key } => {
+                dbg!("react {} to {}", key, id);
+                self.react(room, &id, &key).await?
+            }
+            room::net::ActionKind::MarkRead(event_id) => {
+                dbg!("mark read up to {}", event_id);
+                self.mark_read(room, &event_id).await?
+            }
+            room::net::ActionKind::Typing(typing) => {
+                dbg!("typing {}", typing);
+                self.set_typing(room, typing).await?
+            }
+            room::net::ActionKind::Redact { id, reason } => {
+                dbg!("redact {}", id);
+                self.redact(room, &id, reason).await?
+            }
+            room::net::ActionKind::Upload(path) => {
+                dbg!("upload {}", path.display());
+                self.upload(room, path).await
+            }
+            room::net::ActionKind::Download { url, name, open } => {
+                dbg!("download {}", url);
+                self.download(room, &url, name, open)?
+            }
+            room::net::ActionKind::NewRoom(_) => {
+                return Err(ErrorBatch::from((
+                    room,
+                    "How could a matrix room generate another chat room?".to_string(),
+                )))
+            }
+        }
+        Ok(())
+    }
+
+    async fn process_sync_result(&mut self, res: SyncResult) -> Result<(), ErrorBatch> {
+        // Discard responses still in flight when we disconnected
+        if self.client.is_none() {
+            return Ok(());
+        }
+        match res {
+            Ok(resp) => {
+                self.set_state(State::Connected).await;
+                let res = self.sync(resp).await;
+                // Send what was queued while offline
+                let mut errors = vec![];
+                let ids: Vec<_> = self.rooms.keys().copied().collect();
+                for id in ids.into_iter() {
+                    if let Err(mut e) = self.flush_queue(id).await {
+                        errors.append(&mut e.errors);
+                    }
+                }
+                match res {
+                    Err(mut e) => {
+                        e.errors.append(&mut errors);
+                        Err(e)
+                    }
+                    Ok(()) if errors.is_empty() => Ok(()),
+                    Ok(()) => Err(ErrorBatch { errors }),
+                }
+            }
+            Err(e) => {
+                // The sync loop stops by itself on failure
+                self.sync_thread_stop.take();
+                if e.is_unknown_token() {
+                    self.client.take();
+                    self.session.take();
+                    self.send_error(&format!("Session closed by the server: {}", e))
+                        .await;
+                    self.open().await;
+                } else {
+                    self.back_off(e).await;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Forward timeline events to the UI. State events only update the room state when `live`,
+    /// as paginated events are older than the known state.
+    async fn send_timeline(
+        &mut self,
+        id: room::Id,
+        events: &[EventResult<RoomEvent>],
+        live: bool,
+    ) -> Vec<Error> {
+        let mut errors = vec![];
+        let own_id = self.own_id();
+        for e in events.iter() {
+            let e = match e {
+                EventResult::Ok(e) => e,
+                EventResult::Err(e) => match render::raw_event(e.json()) {
+                    Some(render::RawEvent {
+                        sender,
+                        date,
+                        mut event,
+                    }) => {
+                        if let NetEventKind::Message(message) = &mut event {
+                            message.own = sender == own_id;
+                        }
+                        let source = self.source(id, &sender);
+                        self.send_as(id, date, Some(source), event).await;
+                        continue;
+                    }
+                    None => {
+                        errors.push(Error {
+                            id,
+                            error: format!("room timeline error: {:?}", e),
+                        });
+                        continue;
+                    }
+                },
+            };
+            let date = render::date(e);
+            if let RoomEvent::RoomMessage(m) = e {
+                dbg!("Send msg as {}", id);
+                let source = self.source(id, &m.sender.to_string());
+                let mut message = render::message(&m.content);
+                message.id = Some(m.event_id.to_string());
+                message.own = m.sender.to_string() == own_id;
+                message.txn_id = render::txn_id(m.unsigned.as_ref());
+                self.send_as(id, date, Some(source), NetEventKind::Message(message))
+                    .await;
+                continue;
+            }
+            if let RoomEvent::RoomRedaction(r) = e {
+                let source = self.source(id, &r.sender.to_string());
+                let redaction = event::Redaction {
+                    id: r.redacts.to_string(),
+                    reason: r.content.reason.clone(),
+                };
+                self.send_as(id, date, Some(source), NetEventKind::Redaction(redaction))
+                    .await;
+                continue;
+            }
+            match e {
+                RoomEvent::CustomRoom(c) if c.event_type == compose::REACTION_EVENT_TYPE => {
+                    if let Some(mut reaction) = render::reaction(c) {
+                        reaction.own = c.sender.to_string() == own_id;
+                        let source = self.source(id, &c.sender.to_string());
+                        self.send_as(id, date, Some(source), NetEventKind::Reaction(reaction))
+                            .await;
+                    }
+                    continue;
+                }
+                _ => (),
+            }
+
+            // Describe state changes before applying them, to use the previous names
+            if let Some(info) = render::info(e, &self.rooms[&id]) {
+                self.send_as(id, date, None, NetEventKind::Info(info)).await;
+            }
+            if !live {
+                continue;
+            }
+            if let Some(update) = rooms::timeline_state_update(e) {
+                self.rooms.get_mut(&id).unwrap().set_state(update);
+            }
+            match e {
+                RoomEvent::RoomMember(m) => self.update_member(id, &m.state_key, &m.content).await,
+                RoomEvent::RoomPowerLevels(p) => self.update_power_levels(id, &p.content),
+                _ => (),
+            }
+        }
+        errors
+    }
+
+    /// Forward the receipts and typing notifications of the other users, and our read marker.
+    async fn send_read_state(&mut self, id: room::Id, json: &serde_json::Value) {
+        let own_id = self.own_id();
+        for (user_id, event_id) in render::receipts(json).into_iter() {
+            if user_id != own_id {
+                let source = self.source(id, &user_id);
+                self.send_current_as_by(id, source, NetEventKind::Receipt(event_id))
+                    .await;
+            }
+        }
+        if let Some(event_id) = render::fully_read(json) {
+            self.send_current_as(id, NetEventKind::FullyRead(event_id))
+                .await;
+        }
+        if let Some(user_ids) = render::typing(json) {
+            let sources = user_ids
+                .iter()
+                .filter(|user_id| **user_id != own_id)
+                .map(|user_id| self.source(id, user_id))
+                .collect();
+            self.send_current_as(id, NetEventKind::Typing(sources))
+                .await;
+        }
+    }
+
+    /// Describe a user as the author of an event displayed in a room.
+    fn source(&mut self, id: room::Id, user_id: &str) -> Source {
+        let room = self.rooms.get_mut(&id).unwrap();
+        room.add_sender(user_id);
+        Source::new(user_id, &room.display_name(user_id))
+    }
+
+    async fn update_member(&mut self, id: room::Id, user_id: &str, content: &MemberEventContent) {
+        let room = self.rooms.get_mut(&id).unwrap();
+        let renamed = room.set_member(
+            user_id,
+            Member {
+                display_name: content.displayname.clone(),
+                membership: content.membership,
+            },
+        );
+        let renamed: Vec<_> = renamed
+            .iter()
+            .map(|user_id| Source::new(user_id, &room.display_name(user_id)))
+            .collect();
+        for source in renamed.into_iter() {
+            self.send_current_as(id, NetEventKind::Rename(source)).await;
+        }
+    }
+
+    fn update_power_levels(&mut self, id: room::Id, content: &PowerLevelsEventContent) {
+        let users = content
+            .users
+            .iter()
+            .map(|(user_id, level)| (user_id.to_string(), i64::from(*level)))
+            .collect();
+        self.rooms
+            .get_mut(&id)
+            .unwrap()
+            .set_power_levels(users, i64::from(content.users_default));
+    }
+
+    /// Fetch the full member list of a room once, in a dedicated task handing the response over
+    /// to `set_members`.
+    fn load_members(&mut self, id: room::Id) -> Result<(), ErrorBatch> {
+        let room = &self.rooms[&id];
+        if room.members_loaded || room.members_loading {
+            return Ok(());
+        }
+        let client = match self.client.as_ref() {
+            Some(client) => client.clone(),
+            None => return Err(ErrorBatch::from((id, "Not connected to the server"))),
+        };
+        let request = get_member_events::Request {
+            room_id: room.matrix_id.clone(),
+        };
+        self.rooms.get_mut(&id).unwrap().members_loading = true;
+        let mut results = self.internal_sender.clone();
+        tokio::spawn(async move {
+            let res = client.request(request).await.map_err(ApiError::from);
+            let _ = results.send(Stimulus::Members(id, res)).await;
+        });
+        Ok(())
+    }
+
+    async fn set_members(&mut self, id: room::Id, res: MembersResult) -> Result<(), ErrorBatch> {
+        // The room may have been closed in the meantime
+        let room = match self.rooms.get_mut(&id) {
+            Some(room) => room,
+            None => return Ok(()),
+        };
+        room.members_loading = false;
+        let resp = res.map_err(|e| {
+            ErrorBatch::from((id, format!("Failed to load the room members: {}", e)))
+        })?;
+        room.members_loaded = true;
+        let mut errors = vec![];
+        for e in resp.chunk.iter() {
+            match e {
+                EventResult::Ok(m) => self.update_member(id, &m.state_key, &m.content).await,
+                EventResult::Err(e) => errors.push(Error {
+                    id,
+                    error: format!("room member error: {:?}", e),
+                }),
+            }
+        }
+        self.publish_state(id).await;
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(ErrorBatch { errors })
+        }
+    }
+
+    /// Process the partial state given with an invitation, and tell who sent it.
+    async fn process_invite_state(
+        &mut self,
+        id: room::Id,
+        events: &[EventResult<StrippedState>],
+    ) -> Vec<Error> {
+        let own_id = self.own_id();
+        let mut inviter = None;
+        let mut errors = vec![];
+        for e in events.iter() {
+            match e {
+                EventResult::Ok(StrippedState::RoomMember(m)) => {
+                    if m.state_key == own_id && m.content.membership == MembershipState::Invite {
+                        inviter = Some(m.sender.to_string());
+                    }
+                    self.update_member(id, &m.state_key, &m.content).await
+                }
+                EventResult::Ok(e) => {
+                    if let Some(update) = rooms::stripped_state_update(e) {
+                        self.rooms.get_mut(&id).unwrap().set_state(update);
+                    }
+                }
+                EventResult::Err(e) => errors.push(Error {
+                    id,
+                    error: format!("room invite state error: {:?}", e),
+                }),
+            }
+        }
+        self.publish_state(id).await;
+
+        let room = &self.rooms[&id];
+        let inviter = inviter.map(|user_id| Source::new(&user_id, &room.display_name(&user_id)));
+        self.send_current_as(id, NetEventKind::Invite(event::Invite { inviter }))
+            .await;
+        errors
+    }
+
+    async fn process_state(
+        &mut self,
+        id: room::Id,
+        events: &[EventResult<StateEvent>],
+    ) -> Vec<Error> {
+        let mut errors = vec![];
+        for e in events.iter() {
+            match e {
+                EventResult::Ok(StateEvent::RoomMember(m)) => {
+                    self.update_member(id, &m.state_key, &m.content).await
+                }
+                EventResult::Ok(StateEvent::RoomPowerLevels(p)) => {
+                    self.update_power_levels(id, &p.content)
+                }
+                EventResult::Ok(e) => {
+                    if let Some(update) = rooms::state_update(e) {
+                        self.rooms.get_mut(&id).unwrap().set_state(update);
+                    }
+                }
+                EventResult::Err(e) => errors.push(Error {
+                    id,
+                    error: format!("room state error: {:?}", e),
+                }),
+            }
+        }
+        errors
+    }
+
+    fn own_id(&self) -> String {
+        match self.session.as_ref() {
+            Some(session) => session.user_id.to_string(),
+            None => String::new(),
+        }
+    }
+
+    /// Send the room state and member changes to the UI.
+    async fn publish_state(&mut self, id: room::Id) {
+        let own_id = self.own_id();
+        let room = self.rooms.get_mut(&id).unwrap();
+        let members = room.member_changes();
+        let changes = room.state_changes(&own_id);
+        for member in members.into_iter() {
+            self.send_current_as(id, NetEventKind::Member(member)).await;
+        }
+        for (key, value) in changes.into_iter() {
+            let change = StateChange {
+                key: key.to_string(),
+                value,
+            };
+            self.send_current_as(id, NetEventKind::State(change)).await;
+        }
+    }
+
+    async fn request_messages(
+        &self,
+        id: room::Id,
+        from: String,
+        to: Option<String>,
+    ) -> Result<get_message_events::IncomingResponse, ErrorBatch> {
+        let client = match self.client.as_ref() {
+            Some(client) => client,
+            None => return Err(ErrorBatch::from((id, "Not connected to the server"))),
+        };
+        client
+            .request(messages_request(
+                self.rooms[&id].matrix_id.clone(),
+                from,
+                to,
+            ))
+            .await
+            .map_err(|e| {
+                let error = format!("Failed to load the room history: {}", ApiError::from(e));
+                ErrorBatch::from((id, error))
+            })
+    }
+
+    /// Fetch the page of events preceding the oldest loaded event of a room.
+    async fn load_history(&mut self, id: room::Id) -> Result<(), ErrorBatch> {
+        let room = &self.rooms[&id];
+        let from = match room.prev_batch.as_ref() {
+            Some(from) if !room.history_complete => from.clone(),
+            _ => return Ok(()),
+        };
+        let resp = self.request_messages(id, from, None).await?;
+
+        let room = self.rooms.get_mut(&id).unwrap();
+        room.history_complete = resp.chunk.is_empty();
+        room.prev_batch = Some(resp.end);
+        let errors = self.send_timeline(id, &resp.chunk, false).await;
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(ErrorBatch { errors })
+        }
+    }
+
+    /// Fetch a single message, for the timeline entries referencing it.
+    async fn load_event(&mut self, id: room::Id, event_id: &str) -> Result<(), ErrorBatch> {
+        let event_id = EventId::try_from(event_id)
+            .map_err(|e| ErrorBatch::from((id, format!("Bad event ID '{}': {}", event_id, e))))?;
+        let resp = self
+            .client()?
+            .request(get_context::Request {
+                event_id,
+                limit: 0,
+                room_id: self.rooms[&id].matrix_id.clone(),
+            })
+            .await
+            .map_err(|e| {
+                ErrorBatch::from((id, format!("Event fetch failed: {}", ApiError::from(e))))
+            })?;
+        let m = match resp.event {
+            EventResult::Ok(only::RoomEvent::RoomMessage(m)) => m,
+            // Only messages are quoted
+            EventResult::Ok(_) => return Ok(()),
+            EventResult::Err(e) => {
+                return Err(ErrorBatch::from((id, format!("Bad event: {:?}", e))));
+            }
+        };
+        let source = self.source(id, &m.sender.to_string());
+        let mut message = render::message(&m.content);
+        message.id = Some(m.event_id.to_string());
+        message.own = m.sender.to_string() == self.own_id();
+        let date = usize::try_from(u64::from(m.origin_server_ts)).unwrap_or_default();
+        self.send_as(id, date, Some(source), NetEventKind::Referenced(message))
+            .await;
+        Ok(())
+    }
+
+    async fn redact(
+        &mut self,
+        id: room::Id,
+        event_id: &str,
+        reason: Option<String>,
+    ) -> Result<(), ErrorBatch> {
+        let event_id = EventId::try_from(event_id)
+            .map_err(|e| ErrorBatch::from((id, format!("Bad event ID '{}': {}", event_id, e))))?;
+        let txn_id = self.txn_id();
+        self.client()?
+            .request(redact_event::Request {
+                event_id,
+                reason,
+                room_id: self.rooms[&id].matrix_id.clone(),
+                txn_id,
+            })
+            .await
+            .map_err(|e| {
+                ErrorBatch::from((id, format!("Deletion failed: {}", ApiError::from(e))))
+            })?;
+        Ok(())
+    }
+
+    /// Transaction ID unique across runs, for the server not to take new messages for retries.
+    fn txn_id(&mut self) -> String {
+        format!("{}.{}", self.txn_prefix, self.msg_sn.next().unwrap())
+    }
+
+    async fn send_delivery(
+        &mut self,
+        id: room::Id,
+        txn_id: &str,
+        delivery: Delivery,
+        event_id: Option<String>,
+    ) {
+        let update = event::DeliveryUpdate {
+            txn_id: txn_id.to_string(),
+            delivery,
+            id: event_id,
+        };
+        self.send_current_as(id, NetEventKind::Delivery(update))
+            .await;
+    }
+
+    async fn send_progress(&mut self, id: room::Id, status: String) {
+        self.send_current_as(id, NetEventKind::Progress(status))
+            .await;
+    }
+
+    /// Have an attachment saved to the downloads directory, to open it once done if asked to.
+    fn download(
+        &mut self,
+        id: room::Id,
+        url: &str,
+        name: String,
+        open: bool,
+    ) -> Result<(), ErrorBatch> {
+        let (server, media_id) = media::parse_mxc(url)
+            .ok_or_else(|| ErrorBatch::from((id, format!("Bad content URI '{}'", url))))?;
+        let cached = match dirs::cache_dir() {
+            Some(dir) => media::cache_path(&dir, (server, media_id)),
+            None => return Err(ErrorBatch::from((id, "No cache directory"))),
+        };
+        let dir =
+            match self.downloads.clone() {
+                Some(dir) => dir,
+                None => return Err(ErrorBatch::from((
+                    id,
+                    "No downloads directory, set one with the downloads command of the server room",
+                ))),
+            };
+        let download = transfer::Download {
+            media: (server.to_string(), media_id.to_string()),
+            cached,
+            dir,
+            name,
+            open,
+        };
+        transfer::download(
+            self.transfer_conf(id),
+            download,
+            self.internal_sender.clone(),
+        );
+        Ok(())
+    }
+
+    async fn open_download(
+        &mut self,
+        id: room::Id,
+        res: transfer::DownloadResult,
+    ) -> Result<(), ErrorBatch> {
+        let saved = res.map_err(|e| ErrorBatch::from((id, e)))?;
+        self.send_progress(id, format!("Saved {}", saved.path.display()))
+            .await;
+        if saved.open {
+            self.open_file(id, &saved.path)?;
+        }
+        Ok(())
+    }
+
+    /// Hand a file over to the opener command, without waiting for it.
+    fn open_file(&self, id: room::Id, path: &std::path::Path) -> Result<(), ErrorBatch> {
+        let (program, args) = match self.opener.as_ref().and_then(|o| o.split_first()) {
+            Some(opener) => opener,
+            None => {
+                return Err(ErrorBatch::from((
+                    id,
+                    "No opener command, set one with the opener command of the server room",
+                )))
+            }
+        };
+        // The terminal belongs to the UI
+        let child = tokio::process::Command::new(program)
+            .args(args)
+            .arg(path)
+            .stdin(std::process::Stdio::null())
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null())
+            .spawn()
+            .map_err(|e| ErrorBatch::from((id, format!("Unable to run {}: {}", program, e))))?;
+        tokio::spawn(async move {
+            let _ = child.await;
+        });
+        Ok(())
+    }
+
+    /// What the media transfers made for a room need to know of the server.
+    fn transfer_conf(&self, id: room::Id) -> transfer::Conf {
+        transfer::Conf {
+            homeserver: self.conf.url.clone(),
+            access_token: self.session.as_ref().map(|s| s.access_token.clone()),
+            room: id,
+            input: self.input.clone(),
+        }
+    }
+
+    /// Share a local file: have it uploaded with its thumbnail to the media repository, then
+    /// send the message pointing at them once done.
+    async fn upload(&mut self, id: room::Id, path: PathBuf) {
+        self.send_progress(id, format!("Reading {}…", path.display()))
+            .await;
+        transfer::upload(self.transfer_conf(id), path, self.internal_sender.clone());
+    }
+
+    async fn send_attachment(
+        &mut self,
+        id: room::Id,
+        res: transfer::UploadResult,
+    ) -> Result<(), ErrorBatch> {
+        let transfer::Uploaded {
+            file,
+            url,
+            thumbnail_url,
+        } = res.map_err(|e| ErrorBatch::from((id, e)))?;
+        // The room may have been closed in the meantime
+        let room_id = match self.rooms.get(&id) {
+            Some(room) => room.matrix_id.clone(),
+            None => return Ok(()),
+        };
+        let txn_id = self.txn_id();
+        self.client()?
+            .request(api::send_message_event::Request {
+                room_id,
+                event_type: compose::MESSAGE_EVENT_TYPE.to_string(),
+                txn_id,
+                data: compose::attachment_content(&file, &url, thumbnail_url.as_deref()),
+            })
+            .await
+            .map_err(|e| {
+                let error = format!("Sending {} failed: {}", file.name, ApiError::from(e));
+                ErrorBatch::from((id, error))
+            })?;
+        self.send_progress(id, format!("Sent {}", file.name)).await;
+        Ok(())
+    }
+
+    /// Show a message not sent yet in its room.
+    async fn send_echo(
+        &mut self,
+        id: room::Id,
+        txn_id: &str,
+        publish: &Publish,
+        delivery: Delivery,
+    ) {
+        if let Some(mut echo) = compose::local_echo(publish, txn_id) {
+            echo.delivery = Some(delivery);
+            let own_id = self.own_id();
+            let source = self.source(id, &own_id);
+            self.send_current_as_by(id, source, NetEventKind::Message(echo))
+                .await;
+        }
+    }
+
+    /// Show the messages left in the queue of a room by a previous run.
+    async fn echo_queue(&mut self, id: room::Id) {
+        let matrix_id = self.rooms[&id].matrix_id.to_string();
+        for queued in self.queue.get(&matrix_id).to_vec().into_iter() {
+            let delivery = if queued.failed {
+                Delivery::Failed
+            } else {
+                Delivery::Queued
+            };
+            self.send_echo(id, &queued.txn_id, &queued.publish, delivery)
+                .await;
+        }
+    }
+
+    /// Send the queued messages of a room in order, as long as the server is reachable.
+    ///
+    /// Messages rejected by the server stay in the queue, marked as failed, until they are retried
+    /// or cancelled.
+    async fn flush_queue(&mut self, id: room::Id) -> Result<(), ErrorBatch> {
+        let mut errors = vec![];
+        let matrix_id = self.rooms[&id].matrix_id.to_string();
+        while self.state == State::Connected && self.client.is_some() {
+            let queued = match self.queue.next(&matrix_id) {
+                Some(queued) => queued.clone(),
+                None => break,
+            };
+            let txn_id = queued.txn_id;
+            self.send_delivery(id, &txn_id, Delivery::Pending, None)
+                .await;
+            let res = self
+                .client()?
+                .request(api::send_message_event::Request {
+                    room_id: self.rooms[&id].matrix_id.clone(),
+                    event_type: compose::MESSAGE_EVENT_TYPE.to_string(),
+                    txn_id: txn_id.clone(),
+                    data: compose::message_content(&self.rooms[&id].matrix_id, &queued.publish),
+                })
+                .await
+                .map_err(ApiError::from);
+            let saved = match res {
+                Ok(resp) => {
+                    let event_id = Some(resp.event_id.to_string());
+                    self.send_delivery(id, &txn_id, Delivery::Sent, event_id)
+                        .await;
+                    self.queue.remove(&matrix_id, &txn_id).map(|_| ())
+                }
+                // Wait for the next sync to tell the connection is back
+                Err(e) if e.is_transient() => {
+                    dbg!("sending {} failed: {}", txn_id, e);
+                    self.send_delivery(id, &txn_id, Delivery::Queued, None)
+                        .await;
+                    break;
+                }
+                Err(e) => {
+                    errors.push(Error {
+                        id,
+                        error: format!("Sending failed: {}", e),
+                    });
+                    match queued.publish.relation {
+                        // Edits have no entry of their own to retry them from
+                        Some(Relation::Replace(_)) => {
+                            self.queue.remove(&matrix_id, &txn_id).map(|_| ())
+                        }
+                        _ => {
+                            self.send_delivery(id, &txn_id, Delivery::Failed, None)
+                                .await;
+                            self.queue
+                                .update(&matrix_id, &txn_id, |q| q.failed = true)
+                                .map(|_| ())
+                        }
+                    }
+                }
+            };
+            if let Err(e) = saved {
+                errors.push(Error {
+                    id,
+                    error: format!("Unable to save the outgoing messages: {}", e),
+                });
+            }
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(ErrorBatch { errors })
+        }
+    }
+
+    async fn mark_read(&mut self, id: room::Id, event_id: &str) -> Result<(), ErrorBatch> {
+        let event_id = EventId::try_from(event_id)
+            .map_err(|e| ErrorBatch::from((id, format!("Bad event ID '{}': {}", event_id, e))))?;
+        self.client()?
+            .request(set_read_marker::Request {
+                room_id: self.rooms[&id].matrix_id.clone(),
+                fully_read: event_id.clone(),
+                read_receipt: Some(event_id),
+            })
+            .await
+            .map_err(|e| {
+                ErrorBatch::from((
+                    id,
+                    format!("Read marker update failed: {}", ApiError::from(e)),
+                ))
+            })?;
+        Ok(())
+    }
+
+    async fn set_typing(&mut self, id: room::Id, typing: bool) -> Result<(), ErrorBatch> {
+        let user_id = match self.session.as_ref() {
+            Some(session) => session.user_id.clone(),
+            None => return Err(ErrorBatch::from((id, "Not connected to the server"))),
+        };
+        self.client()?
+            .request(create_typing_event::Request {
+                room_id: self.rooms[&id].matrix_id.clone(),
+                timeout: if typing {
+                    Some(UInt::from(TYPING_TIMEOUT))
+                } else {
+                    None
+                },
+                typing,
+                user_id,
+            })
+            .await
+            .map_err(|e| {
+                ErrorBatch::from((
+                    id,
+                    format!("Typing notification failed: {}", ApiError::from(e)),
+                ))
+            })?;
+        Ok(())
+    }
+
+    async fn react(&mut self, id: room::Id, event_id: &str, key: &str) -> Result<(), ErrorBatch> {
+        let txn_id = self.txn_id();
+        self.client()?
+            .request(api::send_message_event::Request {
+                room_id: self.rooms[&id].matrix_id.clone(),
+                event_type: compose::REACTION_EVENT_TYPE.to_string(),
+                txn_id,
+                data: compose::reaction_content(event_id, key),
+            })
+            .await
+            .map_err(|e| {
+                ErrorBatch::from((id, format!("Reaction failed: {}", ApiError::from(e))))
+            })?;
+        Ok(())
+    }
+
+    /// Fetch the events skipped by the server between two syncs in a dedicated task, handing
+    /// each page over to `send_gap`.
+    fn fill_gap(&mut self, id: room::Id, from: String, to: String) -> Result<(), ErrorBatch> {
+        let client = self.client()?.clone();
+        let room_id = self.rooms[&id].matrix_id.clone();
+        let mut results = self.internal_sender.clone();
+        tokio::spawn(async move {
+            let mut from = from;
+            for _ in 0..GAP_MAX_PAGES {
+                let request = messages_request(room_id.clone(), from, Some(to.clone()));
+                let resp = match client.request(request).await {
+                    Ok(resp) => resp,
+                    Err(e) => {
+                        let error =
+                            format!("Failed to load the room history: {}", ApiError::from(e));
+                        let _ = results.send(Stimulus::Gap(id, Err(error))).await;
+                        return;
+                    }
+                };
+                let done = resp.chunk.len() < HISTORY_PAGE as usize || resp.end == to;
+                let sent = results.send(Stimulus::Gap(id, Ok(resp.chunk))).await;
+                if sent.is_err() || done {
+                    return;
+                }
+                from = resp.end;
+            }
+            let error = "Too many missed events, some messages are not shown".to_string();
+            let _ = results.send(Stimulus::Gap(id, Err(error))).await;
+        });
+        Ok(())
+    }
+
+    async fn send_gap(&mut self, id: room::Id, res: GapResult) -> Result<(), ErrorBatch> {
+        // The room may have been closed in the meantime
+        if !self.rooms.contains_key(&id) {
+            return Ok(());
+        }
+        let events = res.map_err(|e| ErrorBatch::from((id, e)))?;
+        let errors = self.send_timeline(id, &events, false).await;
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(ErrorBatch { errors })
+        }
+    }
+
+    // TODO Split
+    async fn sync(&mut self, resp: IncomingResponse) -> Result<(), ErrorBatch> {
+        dbg!("sync");
+        let mut errors = vec![];
+        // The direct chats come with the first sync, then each time they change
+        if let Some(direct) = render::account_data(&resp.account_data, DIRECT_EVENT_TYPE) {
+            self.direct = render::direct_rooms(direct);
+            let ids: Vec<room::Id> = self.rooms.keys().copied().collect();
+            for id in ids.into_iter() {
+                self.set_direct_state(id);
+                self.publish_state(id).await;
+            }
+        }
+        for (name, _) in resp.rooms.leave.iter() {
+            dbg!("{} room left", name);
+            // We left from another client, or were kicked or banned
+            let id = self.rooms_by_name.get(name).copied();
+            if let Some(id) = id {
+                let content = format!("Left room {}", self.rooms[&id].name(&self.own_id()));
+                self.send_current(NetEventKind::Info(content)).await;
+                self.close_room(id).await;
+            }
+        }
+        for (name, data) in resp.rooms.join.iter() {
+            dbg!("{} room joined", name);
+            if !self.rooms_by_name.contains_key(name) {
+                self.spawn_room(name, None).await.unwrap();
+            }
+            let id = self.rooms_by_name.get(name).copied().unwrap();
+            let room = self.rooms.get_mut(&id).unwrap();
+            if !room.joined {
+                room.joined = true;
+                self.send_current_as(id, NetEventKind::Connected).await;
+            }
+
+            // Remember where the history starts for newly seen rooms, and fill the hole left by
+            // truncated timelines
+            let timeline = &data.timeline;
+            let room = self.rooms.get_mut(&id).unwrap();
+            let first_sync = room.prev_batch.is_none();
+            if first_sync {
+                room.prev_batch = Some(timeline.prev_batch.clone());
+            } else if timeline.limited {
+                if let Some(since) = self.last_sync.clone() {
+                    if let Err(mut e) = self.fill_gap(id, timeline.prev_batch.clone(), since) {
+                        errors.append(&mut e.errors);
+                    }
+                }
+            }
+
+            errors.append(&mut self.process_state(id, &data.state.events).await);
+            errors.append(&mut self.send_timeline(id, &timeline.events, true).await);
+            self.set_direct_state(id);
+            self.publish_state(id).await;
+            // Lazy loading leaves out the members that unnamed rooms are named after
+            if first_sync && !self.rooms[&id].is_named() {
+                if let Err(mut e) = self.load_members(id) {
+                    errors.append(&mut e.errors);
+                }
+            }
+
+            let unread = render::unread(&data.unread_notifications);
+            self.send_current_as(id, NetEventKind::Unread(unread)).await;
+            let read_events = data.ephemeral.events.iter();
+            for json in read_events
+                .chain(data.account_data.events.iter())
+                .filter_map(render::json)
+            {
+                self.send_read_state(id, &json).await;
+            }
+        }
+        for (name, data) in resp.rooms.invite.iter() {
+            dbg!("{} room invitation", name);
+            if !self.rooms_by_name.contains_key(name) {
+                self.spawn_room(name, None).await.unwrap();
+            }
+            let id = self.rooms_by_name.get(name).copied().unwrap();
+            let events = &data.invite_state.events;
+            errors.append(&mut self.process_invite_state(id, events).await);
+        }
+        for presence in resp.presence.events.iter() {
+            dbg!("Presence: {:?}", presence);
+            match presence {
+                // Presence is kept per user by the UI, out of the room timelines
+                EventResult::Ok(p) => {
+                    let id = p.sender.to_string();
+                    let name = p.content.displayname.clone().unwrap_or_else(|| id.clone());
+                    self.send_current_by(
+                        Source::new(&id, &name),
+                        NetEventKind::Presence(Presence {
+                            id: p.sender.to_string(),
+                            display_name: p.content.displayname.clone(),
+                            active: p.content.currently_active,
+                            status_msg: p.content.status_msg.clone(),
+                            presence: p.content.presence,
+                        }),
+                    )
+                    .await
+                }
+                EventResult::Err(err) => errors.push(Error {
+                    id: self.id,
+                    error: err.to_string(),
+                }),
+            }
+        }
+        self.last_sync = Some(resp.next_batch);
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(ErrorBatch { errors })
+        }
+    }
+
+    async fn next_stimulus(&mut self) -> Option<Stimulus> {
+        let action = self.request.recv();
+        let internal = self.internal.recv();
+        futures_util::pin_mut!(action);
+        futures_util::pin_mut!(internal);
+        match future::select(action, internal).await {
+            Either::Left((action, _)) => action.map(Stimulus::Action),
+            Either::Right((stimulus, _)) => stimulus,
+        }
+    }
+
+    pub async fn start(mut self) {
+        dbg!("Starting matrix thread");
+        while let Some(stimulus) = self.next_stimulus().await {
+            dbg!("ev!");
+            let res = match stimulus {
+                Stimulus::Action(action) => {
+                    if action.room == self.id {
+                        self.process_server_action(action.action).await
+                    } else {
+                        self.process_sub_room_action(action).await
+                    }
+                }
+                Stimulus::Sync(res) => self.process_sync_result(res).await,
+                Stimulus::Retry => {
+                    self.retry().await;
+                    Ok(())
+                }
+                Stimulus::Upload(id, res) => self.send_attachment(id, res).await,
+                Stimulus::Download(id, res) => self.open_download(id, res).await,
+                Stimulus::Members(id, res) => self.set_members(id, res).await,
+                Stimulus::Gap(id, res) => self.send_gap(id, res).await,
+            };
+            if let Err(e) = res {
+                for e in e.errors.iter() {
+                    if e.id == self.id {
+                        self.send_error(&e.error).await;
+                    } else {
+                        self.send_error_as(e.id, &e.error).await;
+                    }
+                }
+            }
+            if self.closed {
+                break;
+            }
+        }
+
+        dbg!("Stopping matrix thread");
+        self.stop();
+    }
+
+    fn stop(&mut self) {
+        // TODO Unnecessary
+        self.sync_thread_stop.take();
+        self.retry_thread_stop.take();
+        self.io_thread_stop.take();
+    }
+}
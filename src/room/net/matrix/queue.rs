@@ -0,0 +1,129 @@
+use super::session;
+use crate::dirs;
+use crate::room::net::Publish;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+
+// =============================================================================
+// Outgoing messages
+// =============================================================================
+/// Message waiting to be sent, keeping its transaction ID across attempts.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Queued {
+    pub txn_id: String,
+    pub publish: Publish,
+    // Rejected by the server, only sent again on request
+    pub failed: bool,
+}
+
+/// Messages waiting to be sent, in order, by Matrix room ID.
+///
+/// The queue is saved on disk on every change, so that nothing is lost when the client stops
+/// while offline. Guest accounts only keep it in memory.
+#[derive(Debug, Default)]
+pub struct OutgoingQueue {
+    path: Option<PathBuf>,
+    rooms: HashMap<String, Vec<Queued>>,
+}
+
+impl OutgoingQueue {
+    pub fn open(url: &url::Url, username: Option<&str>) -> Self {
+        let path = username.and_then(|username| {
+            Some(
+                dirs::data_dir()?
+                    .join("queues")
+                    .join(session::account_file_name(url, username)),
+            )
+        });
+        let rooms = path
+            .as_ref()
+            .and_then(|path| fs::read(path).ok())
+            .and_then(|data| serde_json::from_slice(&data).ok())
+            .unwrap_or_default();
+        Self { path, rooms }
+    }
+
+    fn save(&mut self) -> Result<(), String> {
+        self.rooms.retain(|_, queue| !queue.is_empty());
+        let path = match self.path.as_ref() {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        if self.rooms.is_empty() {
+            let _ = fs::remove_file(path);
+            return Ok(());
+        }
+        let data = serde_json::to_vec(&self.rooms).map_err(|e| e.to_string())?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+        }
+        // Messages may be private, and a crash while writing must not lose the previous queue
+        let mut temp = path.as_os_str().to_owned();
+        temp.push(".tmp");
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&temp)
+            .map_err(|e| e.to_string())?;
+        file.write_all(&data).map_err(|e| e.to_string())?;
+        file.sync_all().map_err(|e| e.to_string())?;
+        fs::rename(&temp, path).map_err(|e| e.to_string())
+    }
+
+    pub fn get(&self, room: &str) -> &[Queued] {
+        self.rooms.get(room).map_or(&[], |queue| queue.as_slice())
+    }
+
+    /// Next message of the room to send.
+    pub fn next(&self, room: &str) -> Option<&Queued> {
+        self.get(room).iter().find(|queued| !queued.failed)
+    }
+
+    pub fn push(&mut self, room: &str, txn_id: String, publish: Publish) -> Result<(), String> {
+        self.rooms
+            .entry(room.to_string())
+            .or_default()
+            .push(Queued {
+                txn_id,
+                publish,
+                failed: false,
+            });
+        self.save()
+    }
+
+    pub fn remove(&mut self, room: &str, txn_id: &str) -> Result<Option<Queued>, String> {
+        let queue = match self.rooms.get_mut(room) {
+            Some(queue) => queue,
+            None => return Ok(None),
+        };
+        let queued = match queue.iter().position(|queued| queued.txn_id == txn_id) {
+            Some(i) => queue.remove(i),
+            None => return Ok(None),
+        };
+        self.save().map(|_| Some(queued))
+    }
+
+    /// Change a queued message, which is sent again if it failed. Returns false if it is gone.
+    pub fn update<F>(&mut self, room: &str, txn_id: &str, f: F) -> Result<bool, String>
+    where
+        F: FnOnce(&mut Queued),
+    {
+        let queued = self
+            .rooms
+            .get_mut(room)
+            .and_then(|queue| queue.iter_mut().find(|queued| queued.txn_id == txn_id));
+        match queued {
+            Some(queued) => {
+                f(queued);
+                self.save().map(|_| true)
+            }
+            None => Ok(false),
+        }
+    }
+}
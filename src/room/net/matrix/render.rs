@@ -0,0 +1,470 @@
+use super::compose::{
+    ANNOTATION_RELATION, HTML_FORMAT, MESSAGE_EVENT_TYPE, REACTION_EVENT_TYPE, REPLACE_RELATION,
+};
+use super::rooms::Room;
+use crate::event::{self, Attachment, AttachmentKind, Edit, MessageKind, NetEventKind, Unread};
+use js_int::UInt;
+use ruma_client_api::r0::sync::sync_events::UnreadNotificationsCount;
+use ruma_events::collections::all::RoomEvent;
+use ruma_events::room::member::{MemberEvent, MembershipState};
+use ruma_events::room::message::{MessageEventContent, RelatesTo};
+use ruma_events::{CustomRoomEvent, EventResult, EventType, RoomEvent as _, TryFromRaw};
+use ruma_identifiers::UserId;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+
+// =============================================================================
+// Common event fields
+// =============================================================================
+// The room event enum does not expose the fields shared by all its variants
+macro_rules! with_room_event {
+    ($event:expr, $e:ident => $body:expr) => {
+        match $event {
+            RoomEvent::CallAnswer($e) => $body,
+            RoomEvent::CallCandidates($e) => $body,
+            RoomEvent::CallHangup($e) => $body,
+            RoomEvent::CallInvite($e) => $body,
+            RoomEvent::RoomAliases($e) => $body,
+            RoomEvent::RoomAvatar($e) => $body,
+            RoomEvent::RoomCanonicalAlias($e) => $body,
+            RoomEvent::RoomCreate($e) => $body,
+            RoomEvent::RoomEncrypted($e) => $body,
+            RoomEvent::RoomEncryption($e) => $body,
+            RoomEvent::RoomGuestAccess($e) => $body,
+            RoomEvent::RoomHistoryVisibility($e) => $body,
+            RoomEvent::RoomJoinRules($e) => $body,
+            RoomEvent::RoomMember($e) => $body,
+            RoomEvent::RoomMessage($e) => $body,
+            RoomEvent::RoomMessageFeedback($e) => $body,
+            RoomEvent::RoomName($e) => $body,
+            RoomEvent::RoomPinnedEvents($e) => $body,
+            RoomEvent::RoomPowerLevels($e) => $body,
+            RoomEvent::RoomRedaction($e) => $body,
+            RoomEvent::RoomServerAcl($e) => $body,
+            RoomEvent::RoomThirdPartyInvite($e) => $body,
+            RoomEvent::RoomTombstone($e) => $body,
+            RoomEvent::RoomTopic($e) => $body,
+            RoomEvent::Sticker($e) => $body,
+            RoomEvent::CustomRoom($e) => $body,
+            RoomEvent::CustomState($e) => $body,
+        }
+    };
+}
+
+pub fn sender(event: &RoomEvent) -> &UserId {
+    with_room_event!(event, e => e.sender())
+}
+
+/// Date of an event, in ms since the epoch.
+pub fn date(event: &RoomEvent) -> usize {
+    with_room_event!(event, e => to_usize(e.origin_server_ts()))
+}
+
+fn event_type(event: &RoomEvent) -> EventType {
+    with_room_event!(event, e => ruma_events::Event::event_type(e))
+}
+
+fn to_usize(n: UInt) -> usize {
+    usize::try_from(u64::from(n)).expect("Integer should fit in a usize")
+}
+
+// =============================================================================
+// Messages
+// =============================================================================
+fn attachment(
+    kind: AttachmentKind,
+    name: &str,
+    mimetype: Option<&String>,
+    size: Option<UInt>,
+    url: Option<&String>,
+) -> event::Message {
+    event::Message::new(
+        MessageKind::Attachment(Attachment {
+            kind,
+            name: name.to_string(),
+            mimetype: mimetype.cloned(),
+            size: size.map(u64::from),
+            url: url.cloned(),
+        }),
+        name.to_string(),
+    )
+}
+
+/// Message with its HTML version, if it has one.
+fn formatted(
+    kind: MessageKind,
+    body: &str,
+    format: Option<&String>,
+    formatted_body: Option<&String>,
+) -> event::Message {
+    let mut message = event::Message::new(kind, body.to_string());
+    if format.map(String::as_str) == Some(HTML_FORMAT) {
+        message.formatted = formatted_body.cloned();
+    }
+    message
+}
+
+/// Drop the quote of the replied message from a reply, as it is shown from the replied message.
+fn strip_reply_fallback(message: &mut event::Message) {
+    if message.content.starts_with("> ") {
+        let mut lines = message.content.lines();
+        let mut body = None;
+        while let Some(line) = lines.next() {
+            if line.is_empty() {
+                body = Some(lines.collect::<Vec<_>>().join("\n"));
+                break;
+            }
+            if !line.starts_with('>') {
+                break;
+            }
+        }
+        if let Some(body) = body {
+            message.content = body;
+        }
+    }
+    if let Some(html) = message.formatted.as_mut() {
+        const END: &str = "</mx-reply>";
+        if let Some(end) = html.find(END) {
+            *html = html[end + END.len()..].to_string();
+        }
+    }
+}
+
+fn reply(message: &mut event::Message, relates_to: Option<&RelatesTo>) {
+    if let Some(relates_to) = relates_to {
+        message.in_reply_to = Some(relates_to.in_reply_to.event_id.to_string());
+        strip_reply_fallback(message);
+    }
+}
+
+pub fn message(content: &MessageEventContent) -> event::Message {
+    match content {
+        MessageEventContent::Text(c) => {
+            let mut message = formatted(
+                MessageKind::Text,
+                &c.body,
+                c.format.as_ref(),
+                c.formatted_body.as_ref(),
+            );
+            reply(&mut message, c.relates_to.as_ref());
+            message
+        }
+        MessageEventContent::Emote(c) => formatted(
+            MessageKind::Emote,
+            &c.body,
+            c.format.as_ref(),
+            c.formatted_body.as_ref(),
+        ),
+        MessageEventContent::Notice(c) => {
+            let mut message = event::Message::new(MessageKind::Notice, c.body.clone());
+            reply(&mut message, c.relates_to.as_ref());
+            message
+        }
+        MessageEventContent::ServerNotice(c) => {
+            event::Message::new(MessageKind::Notice, c.body.clone())
+        }
+        MessageEventContent::Image(c) => {
+            let info = c.info.as_ref();
+            attachment(
+                AttachmentKind::Image,
+                &c.body,
+                info.and_then(|i| i.mimetype.as_ref()),
+                info.and_then(|i| i.size),
+                c.url.as_ref().or_else(|| c.file.as_ref().map(|f| &f.url)),
+            )
+        }
+        MessageEventContent::File(c) => {
+            let info = c.info.as_ref();
+            attachment(
+                AttachmentKind::File,
+                c.filename.as_ref().unwrap_or(&c.body),
+                info.and_then(|i| i.mimetype.as_ref()),
+                info.and_then(|i| i.size),
+                c.url.as_ref().or_else(|| c.file.as_ref().map(|f| &f.url)),
+            )
+        }
+        MessageEventContent::Audio(c) => {
+            let info = c.info.as_ref();
+            attachment(
+                AttachmentKind::Audio,
+                &c.body,
+                info.and_then(|i| i.mimetype.as_ref()),
+                info.and_then(|i| i.size),
+                c.url.as_ref().or_else(|| c.file.as_ref().map(|f| &f.url)),
+            )
+        }
+        MessageEventContent::Video(c) => {
+            let info = c.info.as_ref();
+            attachment(
+                AttachmentKind::Video,
+                &c.body,
+                info.and_then(|i| i.mimetype.as_ref()),
+                info.and_then(|i| i.size),
+                c.url.as_ref().or_else(|| c.file.as_ref().map(|f| &f.url)),
+            )
+        }
+        MessageEventContent::Location(c) => {
+            event::Message::new(MessageKind::Location(c.geo_uri.clone()), c.body.clone())
+        }
+        MessageEventContent::__Nonexhaustive => event::Message::text(String::new()),
+    }
+}
+
+// =============================================================================
+// Events our event types reject
+// =============================================================================
+/// Timeline event rejected by the event types, with its author and date.
+pub struct RawEvent {
+    pub sender: String,
+    pub date: usize,
+    pub event: NetEventKind,
+}
+
+/// Make sense of the messages our event types cannot parse: edits, whose relation they do not
+/// know, and messages emptied by a redaction.
+pub fn raw_event(json: &Value) -> Option<RawEvent> {
+    if json["type"] != MESSAGE_EVENT_TYPE {
+        return None;
+    }
+    let sender = json["sender"].as_str()?.to_string();
+    let date = usize::try_from(json["origin_server_ts"].as_u64()?).ok()?;
+    let event_id = json["event_id"].as_str()?.to_string();
+    let txn_id = txn_id(Some(&json["unsigned"]));
+    let content = &json["content"];
+    let relates_to = &content["m.relates_to"];
+
+    let event = if relates_to["rel_type"] == REPLACE_RELATION {
+        let new_content = content["m.new_content"].clone();
+        let mut message = match serde_json::from_value(new_content).ok()? {
+            EventResult::Ok(new_content) => message(&new_content),
+            EventResult::Err(_) => return None,
+        };
+        message.id = Some(event_id);
+        NetEventKind::Edit(Edit {
+            id: relates_to["event_id"].as_str()?.to_string(),
+            message,
+        })
+    } else if content.as_object().is_some_and(|c| c.is_empty()) {
+        let reason = json["unsigned"]["redacted_because"]["content"]["reason"].as_str();
+        let kind = MessageKind::Redacted(reason.map(str::to_string));
+        let mut message = event::Message::new(kind, String::new());
+        message.id = Some(event_id);
+        message.txn_id = txn_id;
+        NetEventKind::Message(message)
+    } else {
+        return None;
+    };
+    Some(RawEvent {
+        sender,
+        date,
+        event,
+    })
+}
+
+/// Transaction ID of an event we sent, from its unsigned data.
+pub fn txn_id(unsigned: Option<&Value>) -> Option<String> {
+    Some(unsigned?["transaction_id"].as_str()?.to_string())
+}
+
+/// Reaction of a custom event, or `None` when it is not one or has been redacted.
+pub fn reaction(event: &CustomRoomEvent) -> Option<event::Reaction> {
+    let relates_to = &event.content["m.relates_to"];
+    if event.event_type != REACTION_EVENT_TYPE || relates_to["rel_type"] != ANNOTATION_RELATION {
+        return None;
+    }
+    Some(event::Reaction {
+        id: event.event_id.to_string(),
+        target: relates_to["event_id"].as_str()?.to_string(),
+        key: relates_to["key"].as_str()?.to_string(),
+        own: false,
+    })
+}
+
+// =============================================================================
+// Read state
+// =============================================================================
+/// JSON of an event, whether our event types could parse it or not.
+///
+/// The receipts and read markers of the sync lack the room ID our event types expect.
+pub fn json<T: Serialize + TryFromRaw>(event: &EventResult<T>) -> Option<Value> {
+    match event {
+        EventResult::Ok(e) => serde_json::to_value(e).ok(),
+        EventResult::Err(e) => Some(e.json().clone()),
+    }
+}
+
+/// Events read by each user, as `(user ID, event ID)` pairs, of an `m.receipt` event.
+pub fn receipts(json: &Value) -> Vec<(String, String)> {
+    let mut receipts = vec![];
+    if json["type"] != "m.receipt" {
+        return receipts;
+    }
+    if let Some(content) = json["content"].as_object() {
+        for (event_id, receipt) in content.iter() {
+            if let Some(users) = receipt["m.read"].as_object() {
+                for user_id in users.keys() {
+                    receipts.push((user_id.clone(), event_id.clone()));
+                }
+            }
+        }
+    }
+    receipts
+}
+
+/// Event our read marker is at, of an `m.fully_read` event.
+pub fn fully_read(json: &Value) -> Option<String> {
+    if json["type"] != "m.fully_read" {
+        return None;
+    }
+    Some(json["content"]["event_id"].as_str()?.to_string())
+}
+
+/// Content of the latest global account data event of type `ty` in a sync response.
+pub fn account_data<'a>(account_data: &'a Value, ty: &str) -> Option<&'a Value> {
+    account_data["events"]
+        .as_array()?
+        .iter()
+        .rev()
+        .find(|event| event["type"] == ty)
+        .map(|event| &event["content"])
+}
+
+/// User of each direct chat, by room ID, of the content of the `m.direct` account data.
+pub fn direct_rooms(content: &Value) -> HashMap<String, String> {
+    let mut rooms = HashMap::new();
+    for (user_id, room_ids) in content.as_object().into_iter().flatten() {
+        for room_id in room_ids.as_array().into_iter().flatten() {
+            if let Some(room_id) = room_id.as_str() {
+                rooms.insert(room_id.to_string(), user_id.clone());
+            }
+        }
+    }
+    rooms
+}
+
+/// Users typing in a room, of an `m.typing` event.
+pub fn typing(json: &Value) -> Option<Vec<String>> {
+    if json["type"] != "m.typing" {
+        return None;
+    }
+    let user_ids = json["content"]["user_ids"].as_array()?;
+    Some(
+        user_ids
+            .iter()
+            .filter_map(|user_id| user_id.as_str())
+            .map(str::to_string)
+            .collect(),
+    )
+}
+
+pub fn unread(count: &UnreadNotificationsCount) -> Unread {
+    Unread {
+        notifications: count.notification_count.map_or(0, u64::from),
+        highlights: count.highlight_count.map_or(0, u64::from),
+    }
+}
+
+// =============================================================================
+// Informational lines
+// =============================================================================
+fn membership(event: &MemberEvent, room: &Room) -> String {
+    let sender = room.display_name(&event.sender.to_string());
+    let target_id = &event.state_key;
+    let target = room.display_name(target_id);
+    let previous = event.prev_content.as_ref();
+    let was = previous.map(|p| p.membership);
+    let by_self = event.sender.to_string() == *target_id;
+
+    match event.content.membership {
+        MembershipState::Join if was == Some(MembershipState::Join) => {
+            let old_name = previous.and_then(|p| p.displayname.as_ref());
+            match (old_name, event.content.displayname.as_ref()) {
+                (old, Some(new)) if old != Some(new) => {
+                    format!("{} is now known as {}", old.unwrap_or(target_id), new)
+                }
+                (Some(old), None) => format!("{} removed their display name", old),
+                _ => format!("{} changed their avatar", target),
+            }
+        }
+        MembershipState::Join => format!(
+            "{} joined",
+            event.content.displayname.as_ref().unwrap_or(&target)
+        ),
+        MembershipState::Invite => format!(
+            "{} invited {}",
+            sender,
+            event.content.displayname.as_ref().unwrap_or(&target)
+        ),
+        MembershipState::Leave if by_self => match was {
+            Some(MembershipState::Invite) => format!("{} rejected the invitation", target),
+            _ => format!("{} left", target),
+        },
+        MembershipState::Leave => match was {
+            Some(MembershipState::Invite) => {
+                format!("{} withdrew the invitation of {}", sender, target)
+            }
+            Some(MembershipState::Ban) => format!("{} unbanned {}", sender, target),
+            _ => format!("{} kicked {}", sender, target),
+        },
+        MembershipState::Ban => format!("{} banned {}", sender, target),
+        MembershipState::Knock => format!("{} asked to join", target),
+        MembershipState::__Nonexhaustive => format!("{} changed membership", target),
+    }
+}
+
+/// Describe a non message event as a line of the timeline, if it is worth displaying.
+pub fn info(event: &RoomEvent, room: &Room) -> Option<String> {
+    let sender = room.display_name(&sender(event).to_string());
+    let line = match event {
+        RoomEvent::RoomMember(e) => membership(e, room),
+        RoomEvent::RoomCreate(_) => format!("{} created the room", sender),
+        RoomEvent::RoomName(e) => match e.content.name() {
+            Some(name) if !name.is_empty() => {
+                format!("{} changed the room name to {}", sender, name)
+            }
+            _ => format!("{} removed the room name", sender),
+        },
+        RoomEvent::RoomTopic(e) if e.content.topic.is_empty() => {
+            format!("{} removed the topic", sender)
+        }
+        RoomEvent::RoomTopic(e) => format!("{} changed the topic to {}", sender, e.content.topic),
+        RoomEvent::RoomCanonicalAlias(e) => match e.content.alias.as_ref() {
+            Some(alias) => format!("{} set the main address to {}", sender, alias),
+            None => format!("{} removed the main address", sender),
+        },
+        RoomEvent::RoomAvatar(_) => format!("{} changed the room avatar", sender),
+        RoomEvent::RoomJoinRules(e) => {
+            format!("{} set the join rule to {}", sender, e.content.join_rule)
+        }
+        RoomEvent::RoomEncryption(_) => format!("{} enabled end-to-end encryption", sender),
+        RoomEvent::RoomEncrypted(_) => format!("Encrypted message from {}", sender),
+        RoomEvent::RoomGuestAccess(e) => {
+            format!("{} set guest access to {}", sender, e.content.guest_access)
+        }
+        RoomEvent::RoomHistoryVisibility(e) => format!(
+            "{} set history visibility to {}",
+            sender, e.content.history_visibility
+        ),
+        RoomEvent::RoomPowerLevels(_) => format!("{} changed the power levels", sender),
+        RoomEvent::RoomPinnedEvents(_) => format!("{} changed the pinned messages", sender),
+        RoomEvent::RoomServerAcl(_) => format!("{} changed the server access list", sender),
+        RoomEvent::RoomThirdPartyInvite(e) => {
+            format!("{} invited {} by email", sender, e.content.display_name)
+        }
+        RoomEvent::RoomTombstone(e) => format!(
+            "{} replaced this room by {}: {}",
+            sender, e.content.replacement_room, e.content.body
+        ),
+        RoomEvent::RoomAliases(_) => return None,
+        RoomEvent::RoomMessageFeedback(_) => return None,
+        RoomEvent::Sticker(e) => format!("{} sent a sticker: {}", sender, e.content.body),
+        RoomEvent::CallInvite(_) => format!("{} started a call", sender),
+        RoomEvent::CallAnswer(_) => format!("{} answered the call", sender),
+        RoomEvent::CallHangup(_) => format!("{} ended the call", sender),
+        RoomEvent::CallCandidates(_) => return None,
+        e => format!("{} sent a {} event", sender, event_type(e)),
+    };
+    Some(line)
+}
@@ -0,0 +1,377 @@
+use crate::event::{self, Source, STATE_NAME, STATE_TOPIC};
+use ruma_events::collections::all::{RoomEvent, StateEvent};
+use ruma_events::room::member::MembershipState;
+use ruma_events::stripped::StrippedState;
+use ruma_identifiers::RoomId as MatrixRoomId;
+use std::collections::{HashMap, HashSet};
+
+// Other state keys shown to the UI
+const STATE_CANONICAL_ALIAS: &str = "canonical_alias";
+const STATE_AVATAR: &str = "avatar";
+const STATE_JOIN_RULE: &str = "join_rule";
+const STATE_ENCRYPTION: &str = "encryption";
+
+// Maximum number of members used to name a room without a name
+const MAX_HEROES: usize = 5;
+
+// =============================================================================
+// Members
+// =============================================================================
+#[derive(Debug, Clone)]
+pub struct Member {
+    pub display_name: Option<String>,
+    pub membership: MembershipState,
+}
+
+impl Member {
+    fn is_present(&self) -> bool {
+        matches!(
+            self.membership,
+            MembershipState::Join | MembershipState::Invite
+        )
+    }
+}
+
+/// Name of a user, disambiguated as described by the Matrix specification given the number of
+/// present members sharing its display name.
+fn disambiguate(user_id: &str, name: Option<&String>, homonyms: usize) -> String {
+    match name {
+        Some(name) if !name.is_empty() => {
+            if homonyms > 1 {
+                format!("{} ({})", name, user_id)
+            } else {
+                name.clone()
+            }
+        }
+        _ => user_id.to_string(),
+    }
+}
+
+// =============================================================================
+// State
+// =============================================================================
+/// Room state tracked by the client, as a key and its new value.
+pub type StateUpdate = (&'static str, Option<String>);
+
+fn non_empty(s: &str) -> Option<String> {
+    if s.is_empty() {
+        None
+    } else {
+        Some(s.to_string())
+    }
+}
+
+// The same state events come in full, timeline and stripped flavours
+macro_rules! state_update {
+    ($event:expr, $kind:ident) => {
+        match $event {
+            $kind::RoomName(e) => Some((STATE_NAME, e.content.name().and_then(non_empty))),
+            $kind::RoomTopic(e) => Some((STATE_TOPIC, non_empty(&e.content.topic))),
+            $kind::RoomCanonicalAlias(e) => Some((
+                STATE_CANONICAL_ALIAS,
+                e.content.alias.as_ref().map(|a| a.to_string()),
+            )),
+            $kind::RoomAvatar(e) => Some((STATE_AVATAR, non_empty(&e.content.url))),
+            $kind::RoomJoinRules(e) => {
+                Some((STATE_JOIN_RULE, Some(e.content.join_rule.to_string())))
+            }
+            _ => None,
+        }
+    };
+}
+
+pub fn state_update(event: &StateEvent) -> Option<StateUpdate> {
+    match event {
+        StateEvent::RoomEncryption(e) => {
+            Some((STATE_ENCRYPTION, Some(e.content.algorithm.to_string())))
+        }
+        e => state_update!(e, StateEvent),
+    }
+}
+
+/// Same as `state_update` for state events found in the timeline.
+pub fn timeline_state_update(event: &RoomEvent) -> Option<StateUpdate> {
+    match event {
+        RoomEvent::RoomEncryption(e) => {
+            Some((STATE_ENCRYPTION, Some(e.content.algorithm.to_string())))
+        }
+        e => state_update!(e, RoomEvent),
+    }
+}
+
+/// Same as `state_update` for the state of rooms we are invited to.
+pub fn stripped_state_update(event: &StrippedState) -> Option<StateUpdate> {
+    state_update!(event, StrippedState)
+}
+
+// =============================================================================
+// Room
+// =============================================================================
+/// Matrix side state of an opened room.
+#[derive(Debug)]
+pub struct Room {
+    pub matrix_id: MatrixRoomId,
+    // Servers to join the room through
+    pub via: Vec<String>,
+
+    // Pagination token pointing before the oldest loaded event
+    pub prev_batch: Option<String>,
+    pub history_complete: bool,
+    // Whether the UI was told the room is joined
+    pub joined: bool,
+
+    // Whether the full member list was fetched, as syncs only carry the members we need
+    pub members_loaded: bool,
+    pub members_loading: bool,
+    members: HashMap<String, Member>,
+    // Users with events displayed in the room
+    senders: HashSet<String>,
+    power_levels: HashMap<String, i64>,
+    default_power_level: i64,
+    // Members as last sent to the UI, and what changed since then
+    shown_members: HashMap<String, (String, MembershipState, i64)>,
+    stale_members: HashSet<String>,
+    stale_names: HashSet<String>,
+    all_members_stale: bool,
+
+    state: HashMap<&'static str, String>,
+    // State as last sent to the UI
+    shown_state: HashMap<&'static str, String>,
+}
+
+impl Room {
+    pub fn new(matrix_id: MatrixRoomId) -> Self {
+        Self {
+            matrix_id,
+            via: vec![],
+            prev_batch: None,
+            history_complete: false,
+            joined: false,
+            members_loaded: false,
+            members_loading: false,
+            members: HashMap::new(),
+            senders: HashSet::new(),
+            power_levels: HashMap::new(),
+            default_power_level: 0,
+            shown_members: HashMap::new(),
+            stale_members: HashSet::new(),
+            stale_names: HashSet::new(),
+            all_members_stale: false,
+            state: HashMap::new(),
+            shown_state: HashMap::new(),
+        }
+    }
+
+    /// Name of a user in this room, disambiguated as described by the Matrix specification.
+    pub fn display_name(&self, user_id: &str) -> String {
+        let name = self
+            .members
+            .get(user_id)
+            .and_then(|m| m.display_name.as_ref());
+        let homonyms = match name {
+            Some(name) => self
+                .members
+                .values()
+                .filter(|m| m.is_present() && m.display_name.as_ref() == Some(name))
+                .count(),
+            None => 0,
+        };
+        disambiguate(user_id, name, homonyms)
+    }
+
+    pub fn add_sender(&mut self, user_id: &str) {
+        if !self.senders.contains(user_id) {
+            self.senders.insert(user_id.to_string());
+        }
+    }
+
+    /// Update a member of the room and list the displayed users whose name changed.
+    pub fn set_member(&mut self, user_id: &str, member: Member) -> Vec<String> {
+        // Only users sharing the old or new name can be affected
+        let mut names = vec![member.display_name.clone()];
+        if let Some(previous) = self.members.get(user_id) {
+            names.push(previous.display_name.clone());
+        }
+        let affected: Vec<String> = self
+            .senders
+            .iter()
+            .filter(|id| {
+                id.as_str() == user_id
+                    || self
+                        .members
+                        .get(id.as_str())
+                        .is_some_and(|m| names.contains(&m.display_name))
+            })
+            .cloned()
+            .collect();
+        let before: Vec<String> = affected.iter().map(|id| self.display_name(id)).collect();
+
+        self.stale_members.insert(user_id.to_string());
+        self.stale_names.extend(names.into_iter().flatten());
+        self.members.insert(user_id.to_string(), member);
+
+        affected
+            .into_iter()
+            .zip(before)
+            .filter(|(id, before)| &self.display_name(id) != before)
+            .map(|(id, _)| id)
+            .collect()
+    }
+
+    pub fn set_power_levels(&mut self, users: HashMap<String, i64>, default: i64) {
+        if default != self.default_power_level {
+            self.all_members_stale = true;
+        } else {
+            let previous = &self.power_levels;
+            let changed = users
+                .iter()
+                .filter(|(id, level)| previous.get(*id) != Some(level))
+                .chain(previous.iter().filter(|(id, _)| !users.contains_key(*id)))
+                .map(|(id, _)| id.clone());
+            self.stale_members.extend(changed);
+        }
+        self.power_levels = users;
+        self.default_power_level = default;
+    }
+
+    pub fn power_level(&self, user_id: &str) -> i64 {
+        self.power_levels
+            .get(user_id)
+            .copied()
+            .unwrap_or(self.default_power_level)
+    }
+
+    /// List the members whose name, membership or power level changed since the last call.
+    ///
+    /// Only the members touched by a state event since then are checked.
+    pub fn member_changes(&mut self) -> Vec<event::Member> {
+        let mut homonyms: HashMap<&String, usize> = HashMap::new();
+        for name in self
+            .members
+            .values()
+            .filter(|m| m.is_present())
+            .filter_map(|m| m.display_name.as_ref())
+        {
+            *homonyms.entry(name).or_insert(0) += 1;
+        }
+
+        let mut changes = vec![];
+        for (user_id, member) in self.members.iter() {
+            let name = member.display_name.as_ref();
+            let stale = self.all_members_stale
+                || self.stale_members.contains(user_id)
+                || name.is_some_and(|name| self.stale_names.contains(name));
+            if !stale {
+                continue;
+            }
+            let count = name.and_then(|name| homonyms.get(name)).copied();
+            let shown = (
+                disambiguate(user_id, name, count.unwrap_or(0)),
+                member.membership,
+                self.power_level(user_id),
+            );
+            if self.shown_members.get(user_id) == Some(&shown) {
+                continue;
+            }
+            changes.push(event::Member {
+                source: Source::new(user_id, &shown.0),
+                membership: shown.1,
+                power_level: shown.2,
+            });
+            self.shown_members.insert(user_id.clone(), shown);
+        }
+        self.stale_members.clear();
+        self.stale_names.clear();
+        self.all_members_stale = false;
+        changes
+    }
+
+    pub fn set_state(&mut self, (key, value): StateUpdate) {
+        match value {
+            Some(value) => self.state.insert(key, value),
+            None => self.state.remove(key),
+        };
+    }
+
+    /// Whether the room has a name of its own, rather than one made up from its members.
+    pub fn is_named(&self) -> bool {
+        self.state.contains_key(STATE_NAME) || self.state.contains_key(STATE_CANONICAL_ALIAS)
+    }
+
+    /// Name of the room as computed by the Matrix specification, from the point of view of
+    /// `own_id`.
+    pub fn name(&self, own_id: &str) -> String {
+        if let Some(name) = self.state.get(STATE_NAME) {
+            return name.clone();
+        }
+        if let Some(alias) = self.state.get(STATE_CANONICAL_ALIAS) {
+            return alias.clone();
+        }
+
+        let mut present: Vec<&String> = self
+            .members
+            .iter()
+            .filter(|(id, m)| id.as_str() != own_id && m.is_present())
+            .map(|(id, _)| id)
+            .collect();
+        if !present.is_empty() {
+            present.sort();
+            return self.list_heroes(&present);
+        }
+
+        // Keep track of who the room was with
+        let mut gone: Vec<&String> = self
+            .members
+            .keys()
+            .filter(|id| id.as_str() != own_id)
+            .collect();
+        if gone.is_empty() {
+            "Empty room".to_string()
+        } else {
+            gone.sort();
+            format!("Empty room (was {})", self.list_heroes(&gone))
+        }
+    }
+
+    fn list_heroes(&self, users: &[&String]) -> String {
+        let mut heroes: Vec<String> = users
+            .iter()
+            .take(MAX_HEROES)
+            .map(|id| self.display_name(id))
+            .collect();
+        if users.len() > heroes.len() {
+            format!(
+                "{} and {} others",
+                heroes.join(", "),
+                users.len() - heroes.len()
+            )
+        } else {
+            let last = heroes.pop().unwrap();
+            if heroes.is_empty() {
+                last
+            } else {
+                format!("{} and {}", heroes.join(", "), last)
+            }
+        }
+    }
+
+    /// List the state changes not yet sent to the UI.
+    pub fn state_changes(&mut self, own_id: &str) -> Vec<StateUpdate> {
+        let mut state = self.state.clone();
+        state.insert(STATE_NAME, self.name(own_id));
+
+        let mut changes: Vec<StateUpdate> = self
+            .shown_state
+            .keys()
+            .filter(|key| !state.contains_key(*key))
+            .map(|key| (*key, None))
+            .collect();
+        for (key, value) in state.iter() {
+            if self.shown_state.get(key) != Some(value) {
+                changes.push((key, Some(value.clone())));
+            }
+        }
+        self.shown_state = state;
+        changes
+    }
+}
@@ -0,0 +1,66 @@
+use crate::dirs;
+use ruma_client::Session;
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+
+// =============================================================================
+// Session persistence
+// =============================================================================
+/// Name of the files holding the data of an account on a homeserver.
+pub fn account_file_name(url: &url::Url, username: &str) -> String {
+    let mut account = format!("{}@{}", username, url.host_str().unwrap_or(""));
+    // Servers on the same host are told apart by their port, left out when it is the default one
+    if let Some(port) = url.port() {
+        account = format!("{}:{}", account, port);
+    }
+    let file_name: String = account
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '-' | '_' | '@' => c,
+            _ => '_',
+        })
+        .collect();
+    file_name + ".json"
+}
+
+/// On-disk storage of the session of a single account on a homeserver.
+pub struct SessionStore {
+    path: PathBuf,
+}
+
+impl SessionStore {
+    pub fn new(url: &url::Url, username: &str) -> Option<Self> {
+        Some(Self {
+            path: dirs::data_dir()?
+                .join("sessions")
+                .join(account_file_name(url, username)),
+        })
+    }
+
+    pub fn load(&self) -> Option<Session> {
+        let data = fs::read(&self.path).ok()?;
+        serde_json::from_slice(&data).ok()
+    }
+
+    pub fn save(&self, session: &Session) -> Result<(), String> {
+        let data = serde_json::to_vec(session).map_err(|e| e.to_string())?;
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+        }
+        // The access token grants full access to the account
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&self.path)
+            .map_err(|e| e.to_string())?;
+        file.write_all(&data).map_err(|e| e.to_string())
+    }
+
+    pub fn remove(&self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
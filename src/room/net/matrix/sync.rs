@@ -0,0 +1,77 @@
+use super::api::sync_events::{self, Filter, IncomingResponse, SetPresence};
+use super::error::ApiError;
+use super::MatrixPresence;
+use super::{Client, Stimulus};
+use crate::net_matrix_dbg as dbg;
+use futures_util::future::{self, Either};
+use tokio::sync::mpsc;
+
+// Only receive the members needed to display the timeline. Our filter definition type does not know
+// about lazy loading, but servers also accept inline JSON filters in place of a filter ID. Without
+// the room summary, the members of unnamed rooms are loaded in full to name them.
+const LAZY_LOADING_FILTER: &str = r#"{"room":{"state":{"lazy_load_members":true}}}"#;
+
+pub type SyncResult = Result<IncomingResponse, ApiError>;
+
+pub struct Conf {
+    pub since: Option<String>,
+    // Server side long polling timeout (ms)
+    pub timeout: u32,
+    // Our presence setting. Syncing marks us online unless told otherwise.
+    pub presence: MatrixPresence,
+}
+
+// =============================================================================
+// Sync loop
+// =============================================================================
+/// Long poll `/sync` in a dedicated task, handing every response over to `results`.
+///
+/// The loop stops after the first failure, or as soon as `stop` is closed, even in the middle of
+/// a request. Retrying is up to the server actor.
+pub fn spawn(
+    client: Client,
+    conf: Conf,
+    mut results: mpsc::Sender<Stimulus>,
+    mut stop: mpsc::Receiver<()>,
+) {
+    tokio::spawn(async move {
+        let Conf {
+            mut since,
+            timeout,
+            presence,
+        } = conf;
+        loop {
+            let request = client.request(sync_events::Request {
+                filter: Some(Filter::FilterId(LAZY_LOADING_FILTER.to_string())),
+                since: since.clone(),
+                full_state: None,
+                // Leave the presence we set ourselves when we are not online
+                set_presence: match presence {
+                    MatrixPresence::Online => None,
+                    _ => Some(SetPresence::Offline),
+                },
+                timeout: Some(timeout.into()),
+            });
+            let stopped = stop.recv();
+            futures_util::pin_mut!(request);
+            futures_util::pin_mut!(stopped);
+            let res = match future::select(request, stopped).await {
+                Either::Left((res, _)) => res,
+                Either::Right(_) => break,
+            };
+
+            let res = match res {
+                Ok(resp) => {
+                    since = Some(resp.next_batch.clone());
+                    Ok(resp)
+                }
+                Err(e) => Err(ApiError::from(e)),
+            };
+            let failed = res.is_err();
+            if results.send(Stimulus::Sync(res)).await.is_err() || failed {
+                break;
+            }
+        }
+        dbg!("Sync loop stopped");
+    });
+}
@@ -0,0 +1,175 @@
+use super::media::{self, LocalFile};
+use super::Stimulus;
+use crate::event::NetEventKind;
+use crate::room;
+use std::path::PathBuf;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+pub type UploadResult = Result<Uploaded, String>;
+pub type DownloadResult = Result<Saved, String>;
+
+/// What a transfer needs to know of the server, to run away from the server actor.
+pub struct Conf {
+    pub homeserver: url::Url,
+    pub access_token: Option<String>,
+    // Room the transfer is made for, showing its progress
+    pub room: room::Id,
+    pub input: mpsc::Sender<room::Event>,
+}
+
+impl Conf {
+    fn access_token(&self) -> Result<&str, String> {
+        self.access_token
+            .as_deref()
+            .ok_or_else(|| "Not connected to the server".to_string())
+    }
+}
+
+/// File shared in the media repository, for the server actor to send the message pointing at it.
+pub struct Uploaded {
+    pub file: LocalFile,
+    pub url: String,
+    pub thumbnail_url: Option<String>,
+}
+
+/// Attachment to save to the downloads directory.
+pub struct Download {
+    // Server name and media ID of its content URI
+    pub media: (String, String),
+    // Where the media cache keeps it
+    pub cached: PathBuf,
+    pub dir: PathBuf,
+    pub name: String,
+    pub open: bool,
+}
+
+/// Attachment saved to the downloads directory, for the server actor to open it if asked to.
+pub struct Saved {
+    pub path: PathBuf,
+    pub open: bool,
+}
+
+// =============================================================================
+// Transfers
+// =============================================================================
+/// Upload a local file with its thumbnail in a dedicated task, handing the outcome over to
+/// `results`.
+pub fn upload(conf: Conf, path: PathBuf, mut results: mpsc::Sender<Stimulus>) {
+    tokio::spawn(async move {
+        let res = upload_file(&conf, path).await;
+        let _ = results.send(Stimulus::Upload(conf.room, res)).await;
+    });
+}
+
+async fn upload_file(conf: &Conf, path: PathBuf) -> UploadResult {
+    let mut file = tokio::task::spawn_blocking(move || LocalFile::read(&path))
+        .await
+        .map_err(|e| e.to_string())
+        .and_then(|res| res)?;
+
+    // The data goes to the request, only its size is needed afterwards
+    let thumbnail_url = match file.thumbnail.as_mut() {
+        Some(thumbnail) => {
+            let label = format!("thumbnail of {}", file.name);
+            let name = format!("thumbnail-{}", file.name);
+            let data = std::mem::take(&mut thumbnail.data);
+            Some(upload_media(conf, &label, &name, &thumbnail.mimetype, data).await?)
+        }
+        None => None,
+    };
+    let data = std::mem::take(&mut file.data);
+    let url = upload_media(conf, &file.name, &file.name, &file.mimetype, data).await?;
+    Ok(Uploaded {
+        file,
+        url,
+        thumbnail_url,
+    })
+}
+
+/// Save an attachment in a dedicated task, fetching it unless it is in the media cache, handing
+/// the outcome over to `results`.
+pub fn download(conf: Conf, download: Download, mut results: mpsc::Sender<Stimulus>) {
+    tokio::spawn(async move {
+        let res = save_file(&conf, download).await;
+        let _ = results.send(Stimulus::Download(conf.room, res)).await;
+    });
+}
+
+async fn save_file(conf: &Conf, download: Download) -> DownloadResult {
+    let Download {
+        media: (server, media_id),
+        cached,
+        dir,
+        name,
+        open,
+    } = download;
+    if !cached.exists() {
+        let access_token = conf.access_token()?;
+        if let Some(dir) = cached.parent() {
+            tokio::fs::create_dir_all(dir)
+                .await
+                .map_err(|e| format!("Unable to create {}: {}", dir.display(), e))?;
+        }
+        let (progress, forwarder) = progress(conf, format!("Downloading {}", name));
+        let res = media::download(
+            &conf.homeserver,
+            access_token,
+            (&server, &media_id),
+            &cached,
+            progress,
+        )
+        .await
+        .map_err(|e| format!("Download of {} failed: {}", name, e));
+        let _ = forwarder.await;
+        res?;
+    }
+    let path = tokio::task::spawn_blocking(move || media::save(&cached, &dir, &name))
+        .await
+        .map_err(|e| e.to_string())
+        .and_then(|res| res)?;
+    Ok(Saved { path, open })
+}
+
+/// Upload data to the media repository, showing the progress in the status line.
+async fn upload_media(
+    conf: &Conf,
+    label: &str,
+    name: &str,
+    mimetype: &str,
+    data: Vec<u8>,
+) -> Result<String, String> {
+    let access_token = conf.access_token()?;
+    let (progress, forwarder) = progress(conf, format!("Uploading {}", label));
+    let res = media::upload(
+        &conf.homeserver,
+        access_token,
+        name,
+        mimetype,
+        data,
+        progress,
+    )
+    .await
+    .map_err(|e| format!("Upload of {} failed: {}", label, e));
+    // Don't let a late percentage overwrite what comes next
+    let _ = forwarder.await;
+    res
+}
+
+/// Channel forwarding the percentages it receives to the status line, after `status`.
+///
+/// The forwarding task ends once the channel is dropped.
+fn progress(conf: &Conf, status: String) -> (mpsc::UnboundedSender<u8>, JoinHandle<()>) {
+    let (progress, mut percents) = mpsc::unbounded_channel();
+    let mut input = conf.input.clone();
+    let room = conf.room;
+    let forwarder = tokio::spawn(async move {
+        let event = NetEventKind::Progress(format!("{}…", status));
+        let _ = input.send(event.to_current_event(room, None)).await;
+        while let Some(percent) = percents.recv().await {
+            let event = NetEventKind::Progress(format!("{}: {}%", status, percent));
+            let _ = input.send(event.to_current_event(room, None)).await;
+        }
+    });
+    (progress, forwarder)
+}
@@ -0,0 +1,159 @@
+use percent_encoding::percent_decode_str;
+use ruma_identifiers::RoomIdOrAliasId;
+use std::convert::TryFrom;
+use url::Url;
+
+const MATRIX_TO_HOST: &str = "matrix.to";
+
+// =============================================================================
+// Room targets
+// =============================================================================
+/// Room designated by the user, with the servers that may be used to join it.
+#[derive(Debug)]
+pub struct RoomTarget {
+    pub room: RoomIdOrAliasId,
+    pub via: Vec<String>,
+}
+
+const SYNTAX: &str = "Expected a room ID (!id:server), a room alias (#alias:server), a \
+                      https://matrix.to/#/ link or a matrix: URI";
+
+/// Parse any of the usual ways to designate a Matrix room.
+pub fn parse(input: &str) -> Result<RoomTarget, String> {
+    let input = input.trim();
+    if input.starts_with('!') || input.starts_with('#') {
+        return Ok(RoomTarget {
+            room: parse_id(input)?,
+            via: vec![],
+        });
+    }
+
+    let url = Url::parse(input).map_err(|_| format!("Unknown room '{}'. {}", input, SYNTAX))?;
+    match url.scheme() {
+        "https" | "http" if url.host_str() == Some(MATRIX_TO_HOST) => parse_matrix_to(&url),
+        "matrix" => parse_matrix_uri(&url),
+        _ => Err(format!("Unknown room '{}'. {}", input, SYNTAX)),
+    }
+}
+
+fn parse_id(id: &str) -> Result<RoomIdOrAliasId, String> {
+    RoomIdOrAliasId::try_from(id).map_err(|e| format!("Bad room ID or alias '{}': {}", id, e))
+}
+
+fn decode(s: &str) -> String {
+    percent_decode_str(s).decode_utf8_lossy().into_owned()
+}
+
+fn via(query: Option<&str>) -> Vec<String> {
+    url::form_urlencoded::parse(query.unwrap_or("").as_bytes())
+        .filter(|(key, _)| key == "via")
+        .map(|(_, server)| server.into_owned())
+        .collect()
+}
+
+/// `https://matrix.to/#/<room>[/<event>][?via=<server>]`
+fn parse_matrix_to(url: &Url) -> Result<RoomTarget, String> {
+    let fragment = url.fragment().unwrap_or("");
+    let fragment = fragment.trim_start_matches('/');
+    let mut parts = fragment.splitn(2, '?');
+    let path = parts.next().unwrap_or("");
+    let query = parts.next();
+
+    let id = decode(path.split('/').next().unwrap_or(""));
+    if id.starts_with('@') {
+        return Err(format!("'{}' designates a user, not a room", id));
+    }
+    Ok(RoomTarget {
+        room: parse_id(&id)?,
+        via: via(query),
+    })
+}
+
+/// `matrix:r/<alias>`, `matrix:roomid/<id>`, optionally followed by an event and `?via=<server>`
+fn parse_matrix_uri(url: &Url) -> Result<RoomTarget, String> {
+    let mut segments = url.path().split('/');
+    let sigil = match segments.next() {
+        Some("r") => '#',
+        Some("roomid") => '!',
+        Some("u") => return Err("matrix: URIs of users do not designate a room".to_string()),
+        _ => return Err(format!("Unsupported matrix: URI '{}'", url)),
+    };
+    let id = match segments.next() {
+        Some(id) if !id.is_empty() => decode(id),
+        _ => return Err(format!("Missing room in matrix: URI '{}'", url)),
+    };
+    Ok(RoomTarget {
+        room: parse_id(&format!("{}{}", sigil, id))?,
+        via: via(url.query()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse;
+
+    /// Room and servers of a parsed target.
+    fn target(input: &str) -> (String, Vec<String>) {
+        let target = parse(input).unwrap();
+        (target.room.to_string(), target.via)
+    }
+
+    #[test]
+    fn ids_and_aliases() {
+        assert_eq!(
+            target("!abc:example.org"),
+            ("!abc:example.org".to_string(), vec![])
+        );
+        assert_eq!(
+            target("  #room:example.org "),
+            ("#room:example.org".to_string(), vec![])
+        );
+    }
+
+    #[test]
+    fn matrix_to_links() {
+        assert_eq!(
+            target("https://matrix.to/#/%23room%3Aexample.org"),
+            ("#room:example.org".to_string(), vec![])
+        );
+        assert_eq!(
+            target("https://matrix.to/#/!abc%3Aexample.org/$event%3Aexample.org?via=a.org"),
+            ("!abc:example.org".to_string(), vec!["a.org".to_string()])
+        );
+        assert_eq!(
+            target("https://matrix.to/#/#room:example.org?via=a.org&via=b.org&x=c.org"),
+            (
+                "#room:example.org".to_string(),
+                vec!["a.org".to_string(), "b.org".to_string()]
+            )
+        );
+    }
+
+    #[test]
+    fn matrix_uris() {
+        assert_eq!(
+            target("matrix:r/room:example.org"),
+            ("#room:example.org".to_string(), vec![])
+        );
+        assert_eq!(
+            target("matrix:roomid/abc:example.org/e/event:example.org?via=a.org&via=b.org"),
+            (
+                "!abc:example.org".to_string(),
+                vec!["a.org".to_string(), "b.org".to_string()]
+            )
+        );
+    }
+
+    #[test]
+    fn rejected_input() {
+        assert!(parse("").is_err());
+        assert!(parse("room").is_err());
+        assert!(parse("!abc").is_err());
+        assert!(parse("https://example.org/#/#room:example.org").is_err());
+        assert!(parse("https://matrix.to/#/@user:example.org").is_err());
+        assert!(parse("https://matrix.to/").is_err());
+        assert!(parse("matrix:u/user:example.org").is_err());
+        assert!(parse("matrix:r/").is_err());
+        assert!(parse("matrix:x/room:example.org").is_err());
+    }
+}
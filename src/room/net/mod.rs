@@ -1,4 +1,6 @@
 use crate::room;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
 
 pub mod app;
 pub mod matrix;
@@ -9,12 +11,91 @@ pub struct NewRoom {
     pub command: Vec<String>,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum MessageType {
+    Text,
+    Emote,
+    Notice,
+}
+
+/// Message replied to, with what the reply fallback quotes of it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReplyTo {
+    pub id: String,
+    pub sender: String,
+    pub body: String,
+    pub formatted: Option<String>,
+}
+
+/// Link from a published message to another event of the room.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Relation {
+    InReplyTo(ReplyTo),
+    // New version of the message with this ID
+    Replace(String),
+}
+
+/// Message to publish in a room.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Publish {
+    pub msgtype: MessageType,
+    pub body: String,
+    // HTML version of the body
+    pub formatted_body: Option<String>,
+    pub relation: Option<Relation>,
+}
+
+impl Publish {
+    pub fn new(msgtype: MessageType, body: String) -> Self {
+        Self {
+            msgtype,
+            body,
+            formatted_body: None,
+            relation: None,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum ActionKind {
-    Sync,
     Connect,
     Disconnect,
-    Publish(String),
+    // Leave the room, optionally forgetting it, and remove it from the UI
+    Close {
+        forget: bool,
+    },
+    Publish(Publish),
+    LoadHistory,
+    LoadMembers,
+    // Fetch a message missing from the loaded timeline
+    LoadEvent(String),
+    // Move the read receipt and marker to a message
+    MarkRead(String),
+    Typing(bool),
+    // Send a failed message again, or drop a message not sent yet, by transaction ID
+    Retry(String),
+    Cancel(String),
+    // Change a message not sent yet
+    EditQueued {
+        txn_id: String,
+        publish: Publish,
+    },
+    Redact {
+        id: String,
+        reason: Option<String>,
+    },
+    // Share a local file
+    Upload(PathBuf),
+    // Save the attachment at a content URI (mxc://) under its name, then maybe open it
+    Download {
+        url: String,
+        name: String,
+        open: bool,
+    },
+    React {
+        id: String,
+        key: String,
+    },
     NewRoom(NewRoom),
     // TODO Add configuration action
     // Configuration(String),
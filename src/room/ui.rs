@@ -1,9 +1,48 @@
-use crate::event::{Action, Event, EventProcessor, Key, NetEvent};
+use crate::event::{
+    Action, AppAction, Delivery, DeliveryUpdate, Edit, Event, EventProcessor, Invite, Key,
+    MatrixMembership, Member, Message, MessageKind, NetEvent, NetEventKind, Reaction, Redaction,
+    RoomAction, Source, StateChange, Unread, STATE_NAME, STATE_TOPIC,
+};
+use crate::room::net::{ActionKind, ReplyTo};
+use crate::text::{
+    html,
+    styled_text::{Span, StyledText},
+};
 use crate::widget::{room_entry, room_entry::RoomEntry, scroll::Scroll};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use tui::layout::{Constraint, Direction, Layout};
+use tui::style::{Color, Modifier, Style};
+use tui::widgets::{Paragraph, Text};
 
 use super::{Id, StringId};
 
+// Maximum number of characters of the replied message quoted above a reply
+const QUOTE_LENGTH: usize = 80;
+
+const QUOTE_STYLE: Style = Style {
+    fg: Color::DarkGray,
+    bg: Color::Reset,
+    modifier: Modifier::empty(),
+};
+
+const OWN_REACTION_STYLE: Style = Style {
+    fg: Color::Yellow,
+    bg: Color::Reset,
+    modifier: Modifier::BOLD,
+};
+
+const FAILED_STYLE: Style = Style {
+    fg: Color::Red,
+    bg: Color::Reset,
+    modifier: Modifier::empty(),
+};
+
+const DIVIDER_STYLE: Style = Style {
+    fg: Color::Red,
+    bg: Color::Reset,
+    modifier: Modifier::empty(),
+};
+
 // =============================================================================
 // Defines
 // =============================================================================
@@ -33,6 +72,8 @@ pub enum RequestError {
 pub struct Conf {
     pub alias: StringId,
     pub meta_width: u16,
+    // Whether the published messages are read as markdown
+    pub markdown: bool,
 }
 
 #[derive(Debug)]
@@ -41,10 +82,40 @@ pub struct Room {
     pub conf: Conf,
 
     pub state: HashMap<String, String>,
+    pub members: HashMap<String, Member>,
+    // Pending invitation to join the room
+    pub invite: Option<Invite>,
     pub events: Vec<NetEvent>,
+    // Messages referenced by the timeline but missing from it, by ID
+    pub referenced: HashMap<String, NetEvent>,
+    // IDs of the referenced messages requested to the network side
+    requested: HashSet<String>,
+    // Edits and redactions of messages not loaded yet, by message ID
+    pending_changes: HashMap<String, Vec<NetEvent>>,
+    // Reactions to the messages, by message ID
+    reactions: HashMap<String, Vec<(Source, Reaction)>>,
+    // Notifications not read yet
+    pub unread: Unread,
+    // Event our read marker is at
+    fully_read: Option<String>,
+    // Message the new messages divider is drawn under
+    divider: Option<String>,
+    // The room is displayed
+    viewed: bool,
+    // Other users composing a message
+    typing: Vec<Source>,
+    // Event read by each other user, with the user, by user ID
+    receipts: HashMap<String, (Source, String)>,
+    // Message the next published message replies to
+    pub reply: Option<ReplyTo>,
+    // Message the next published message replaces
+    pub editing: Option<String>,
+    // Transaction ID of the message not sent yet that the next published message replaces
+    pub editing_unsent: Option<String>,
     pub widget: Scroll,
 
     focused: bool,
+    history_pending: bool,
 }
 
 impl Room {
@@ -53,16 +124,811 @@ impl Room {
             id,
             conf,
             state: HashMap::new(),
+            members: HashMap::new(),
+            invite: None,
             events: vec![],
+            referenced: HashMap::new(),
+            requested: HashSet::new(),
+            pending_changes: HashMap::new(),
+            reactions: HashMap::new(),
+            unread: Unread::default(),
+            fully_read: None,
+            divider: None,
+            viewed: false,
+            receipts: HashMap::new(),
+            typing: vec![],
+            reply: None,
+            editing: None,
+            editing_unsent: None,
             widget: Scroll::new(vec![]),
             focused: false,
+            history_pending: false,
+        }
+    }
+
+    fn build_entry(&self, ev: &NetEvent) -> Box<RoomEntry> {
+        let mut sender = ev.source.as_ref().map(|s| s.name.clone());
+        let mut text = StyledText::new(&ev.event.to_string());
+        let mut style = Style::default();
+        if let NetEventKind::Message(Message {
+            formatted: Some(html),
+            ..
+        }) = &ev.event
+        {
+            // Fall back on the plain body when nothing displayable is left
+            let formatted = html::parse(html);
+            if !formatted.is_empty() {
+                text = formatted;
+            }
+        }
+        match &ev.event {
+            NetEventKind::Message(Message {
+                kind: MessageKind::Emote,
+                ..
+            }) => {
+                let name = sender.take().unwrap_or_default();
+                text.prepend(Span::new(&format!("* {} ", name), Style::default()));
+            }
+            NetEventKind::Message(Message {
+                kind: MessageKind::Notice,
+                ..
+            }) => style = style.fg(Color::Cyan),
+            NetEventKind::Message(Message {
+                kind: MessageKind::Redacted(_),
+                ..
+            }) => style = style.fg(Color::DarkGray).modifier(Modifier::ITALIC),
+            NetEventKind::Info(_) => style = style.fg(Color::DarkGray),
+            _ => (),
+        }
+        if let NetEventKind::Message(Message { edited: true, .. }) = &ev.event {
+            let mark = Span::new(" (edited)", QUOTE_STYLE);
+            match text.lines.last_mut() {
+                Some(line) => line.push(mark),
+                None => text.lines.push(vec![mark]),
+            }
+        }
+        if let NetEventKind::Message(Message {
+            delivery: Some(delivery),
+            ..
+        }) = &ev.event
+        {
+            let mark = match delivery {
+                Delivery::Queued => Span::new(" (queued)", QUOTE_STYLE),
+                Delivery::Pending => Span::new(" (sending…)", QUOTE_STYLE),
+                Delivery::Sent => Span::new(" ✓", QUOTE_STYLE),
+                Delivery::Failed => Span::new(" ✗ failed, :retry or :cancel", FAILED_STYLE),
+            };
+            match text.lines.last_mut() {
+                Some(line) => line.push(mark),
+                None => text.lines.push(vec![mark]),
+            }
+        }
+        if let NetEventKind::Message(Message { id: Some(id), .. }) = &ev.event {
+            if let Some(line) = self.reactions_line(id) {
+                text.lines.push(line);
+            }
+            if let Some(span) = self.receipts_span(id) {
+                if let Some(line) = text.lines.last_mut() {
+                    line.push(span);
+                }
+            }
+            if self.divider.as_ref() == Some(id) {
+                text.lines
+                    .push(vec![Span::new("──── New messages ────", DIVIDER_STYLE)]);
+            }
+        }
+        if let NetEventKind::Message(Message {
+            in_reply_to: Some(parent),
+            ..
+        }) = &ev.event
+        {
+            text.lines.insert(0, vec![self.quote(parent)]);
+        }
+
+        Box::new(
+            RoomEntry::styled(
+                room_entry::Meta {
+                    date: ev.date,
+                    sender,
+                },
+                text,
+                room_entry::Conf {
+                    meta_width: self.conf.meta_width,
+                },
+            )
+            .with_style(style),
+        )
+    }
+
+    /// One line summary of a replied message.
+    fn quote(&self, id: &str) -> Span {
+        let quote = match self.find_message(id) {
+            Some((source, message)) => {
+                let name = source.map_or("?", |s| s.name.as_str());
+                let line = message.to_string();
+                let line = line.lines().next().unwrap_or("");
+                if line.chars().count() > QUOTE_LENGTH {
+                    let line: String = line.chars().take(QUOTE_LENGTH).collect();
+                    format!("↳ {}: {}…", name, line)
+                } else {
+                    format!("↳ {}: {}", name, line)
+                }
+            }
+            None => "↳ In reply to a message not loaded yet".to_string(),
+        };
+        Span::new(&quote, QUOTE_STYLE)
+    }
+
+    /// Message of the timeline or referenced by it, with its author.
+    fn find_message(&self, id: &str) -> Option<(Option<&Source>, &Message)> {
+        let id = Some(id.to_string());
+        self.events
+            .iter()
+            .chain(self.referenced.values())
+            .find_map(|ev| match &ev.event {
+                NetEventKind::Message(m) | NetEventKind::Referenced(m) if m.id == id => {
+                    Some((ev.source.as_ref(), m))
+                }
+                _ => None,
+            })
+    }
+
+    fn replies_to(ev: &NetEvent, id: &str) -> bool {
+        match &ev.event {
+            NetEventKind::Message(Message {
+                in_reply_to: Some(parent),
+                ..
+            }) => parent == id,
+            _ => false,
+        }
+    }
+
+    /// Rebuild the entries whose quote changes when a message is received.
+    fn rebuild_replies(&mut self, id: &str) {
+        for i in 0..self.events.len() {
+            if Self::replies_to(&self.events[i], id) {
+                let widget = self.build_entry(&self.events[i]);
+                self.widget.replace(i, widget);
+            }
+        }
+    }
+
+    /// Ask for the replied message when it is not known yet.
+    fn request_parent(&mut self, ev: &NetEvent) -> Vec<Action> {
+        let parent = match &ev.event {
+            NetEventKind::Message(Message {
+                in_reply_to: Some(parent),
+                ..
+            }) => parent,
+            _ => return vec![],
+        };
+        if self.find_message(parent).is_some() || !self.requested.insert(parent.clone()) {
+            return vec![];
+        }
+        vec![Action::Room(RoomAction::LoadEvent {
+            room: self.id,
+            id: parent.clone(),
+        })]
+    }
+
+    fn rename(&mut self, source: Source) {
+        for s in self.typing.iter_mut().filter(|s| s.id == source.id) {
+            s.name = source.name.clone();
+        }
+        if let Some((s, id)) = self.receipts.get_mut(&source.id) {
+            s.name = source.name.clone();
+            let id = id.clone();
+            self.rebuild_message(&id);
+        }
+        for (s, _) in self.reactions.values_mut().flatten() {
+            if s.id == source.id {
+                s.name = source.name.clone();
+            }
+        }
+        for ev in self.referenced.values_mut() {
+            match ev.source.as_mut() {
+                Some(s) if s.id == source.id => s.name = source.name.clone(),
+                _ => (),
+            }
+        }
+        for i in 0..self.events.len() {
+            // Replies quote the name of the author of the replied message
+            let reply = Self::replies_to_any(&self.events[i]);
+            match self.events[i].source.as_mut() {
+                Some(s) if s.id == source.id => s.name = source.name.clone(),
+                _ if reply => (),
+                _ => continue,
+            }
+            let widget = self.build_entry(&self.events[i]);
+            self.widget.replace(i, widget);
+        }
+    }
+
+    fn replies_to_any(ev: &NetEvent) -> bool {
+        match &ev.event {
+            NetEventKind::Message(m) => m.in_reply_to.is_some(),
+            _ => false,
+        }
+    }
+
+    /// Message of the selected entry, to be replied to.
+    fn selected_reply(&self) -> Result<ReplyTo, String> {
+        let ev = match self.widget.selected() {
+            Some(i) => &self.events[i],
+            None => return Err("Select a message first (j/k)".to_string()),
+        };
+        match (&ev.event, ev.source.as_ref()) {
+            (
+                NetEventKind::Message(Message {
+                    id: Some(id),
+                    content,
+                    formatted,
+                    ..
+                }),
+                Some(source),
+            ) => Ok(ReplyTo {
+                id: id.clone(),
+                sender: source.id.clone(),
+                body: content.clone(),
+                formatted: formatted.clone(),
+            }),
+            _ => Err("Only the messages of the server can be replied to".to_string()),
+        }
+    }
+
+    fn message_index(&self, id: &str) -> Option<usize> {
+        let id = Some(id.to_string());
+        self.events.iter().position(|ev| match &ev.event {
+            NetEventKind::Message(m) => m.id == id,
+            _ => false,
+        })
+    }
+
+    /// Apply an edit or a redaction to its message, or keep it until the message is loaded.
+    fn change_message(&mut self, change: NetEvent) {
+        let id = match &change.event {
+            NetEventKind::Edit(Edit { id, .. }) | NetEventKind::Redaction(Redaction { id, .. }) => {
+                id.clone()
+            }
+            _ => return,
+        };
+        if let NetEventKind::Redaction(_) = change.event {
+            if self.remove_reaction(&id) {
+                return;
+            }
+        }
+        match self.message_index(&id) {
+            Some(i) => self.apply_change(i, &change),
+            None => self.pending_changes.entry(id).or_default().push(change),
+        }
+    }
+
+    fn apply_change(&mut self, i: usize, change: &NetEvent) {
+        let ev = &mut self.events[i];
+        // Only the author of a message may edit it
+        let same_author = match (ev.source.as_ref(), change.source.as_ref()) {
+            (Some(a), Some(b)) => a.id == b.id,
+            _ => false,
+        };
+        let message = match &mut ev.event {
+            NetEventKind::Message(m) => m,
+            _ => return,
+        };
+        match &change.event {
+            NetEventKind::Edit(edit) if same_author => {
+                if let MessageKind::Redacted(_) = message.kind {
+                    return;
+                }
+                message.kind = edit.message.kind.clone();
+                message.content = edit.message.content.clone();
+                message.formatted = edit.message.formatted.clone();
+                message.edited = true;
+            }
+            NetEventKind::Redaction(redaction) => {
+                message.kind = MessageKind::Redacted(redaction.reason.clone());
+                message.content.clear();
+                message.formatted = None;
+                message.in_reply_to = None;
+                message.edited = false;
+            }
+            _ => return,
+        }
+        let id = message.id.clone();
+        let widget = self.build_entry(&self.events[i]);
+        self.widget.replace(i, widget);
+        if let Some(id) = id {
+            self.rebuild_replies(&id);
+        }
+    }
+
+    /// Apply the changes received before the message they target.
+    fn apply_pending_changes(&mut self, i: usize, id: &str) {
+        if let Some(mut changes) = self.pending_changes.remove(id) {
+            changes.sort_by_key(|change| change.date);
+            for change in changes.iter() {
+                self.apply_change(i, change);
+            }
         }
     }
+
+    fn rebuild_message(&mut self, id: &str) {
+        if let Some(i) = self.message_index(id) {
+            let widget = self.build_entry(&self.events[i]);
+            self.widget.replace(i, widget);
+        }
+    }
+
+    fn add_reaction(&mut self, source: Source, reaction: Reaction) {
+        // The reaction may have been redacted before we loaded it
+        if let Some(changes) = self.pending_changes.remove(&reaction.id) {
+            let redacted = changes
+                .iter()
+                .any(|change| matches!(change.event, NetEventKind::Redaction(_)));
+            if redacted {
+                return;
+            }
+        }
+        let reactions = self.reactions.entry(reaction.target.clone()).or_default();
+        if reactions.iter().any(|(_, r)| r.id == reaction.id) {
+            return;
+        }
+        let target = reaction.target.clone();
+        reactions.push((source, reaction));
+        self.rebuild_message(&target);
+    }
+
+    /// Forget a redacted reaction, returning whether `id` was one.
+    fn remove_reaction(&mut self, id: &str) -> bool {
+        let target = self.reactions.iter_mut().find_map(|(target, reactions)| {
+            let i = reactions.iter().position(|(_, r)| r.id == id)?;
+            reactions.remove(i);
+            Some(target.clone())
+        });
+        match target {
+            Some(target) => {
+                self.rebuild_message(&target);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Reactions to a message aggregated by key, in order of appearance, with their authors.
+    fn aggregated_reactions(&self, id: &str) -> Vec<(&str, Vec<&Source>, bool)> {
+        let mut keys: Vec<(&str, Vec<&Source>, bool)> = vec![];
+        for (source, reaction) in self.reactions.get(id).into_iter().flatten() {
+            let i = match keys.iter().position(|(key, _, _)| *key == reaction.key) {
+                Some(i) => i,
+                None => {
+                    keys.push((&reaction.key, vec![], false));
+                    keys.len() - 1
+                }
+            };
+            let (_, sources, own) = &mut keys[i];
+            if !sources.iter().any(|s| s.id == source.id) {
+                sources.push(source);
+            }
+            *own |= reaction.own;
+        }
+        keys
+    }
+
+    /// Reaction counts shown under a message, our own reactions being highlighted.
+    fn reactions_line(&self, id: &str) -> Option<Vec<Span>> {
+        let keys = self.aggregated_reactions(id);
+        if keys.is_empty() {
+            return None;
+        }
+        let mut line = vec![];
+        for (i, (key, sources, own)) in keys.iter().enumerate() {
+            if i > 0 {
+                line.push(Span::new("  ", Style::default()));
+            }
+            let style = if *own {
+                OWN_REACTION_STYLE
+            } else {
+                Style::default()
+            };
+            line.push(Span::new(&format!("{} {}", key, sources.len()), style));
+        }
+        Some(line)
+    }
+
+    /// Who reacted to the selected message.
+    fn selected_reactions(&self) -> String {
+        let id = match self.widget.selected().map(|i| &self.events[i].event) {
+            Some(NetEventKind::Message(Message { id: Some(id), .. })) => id,
+            Some(_) => return "Only the messages of the server have reactions".to_string(),
+            None => return "Select a message first (j/k)".to_string(),
+        };
+        let keys = self.aggregated_reactions(id);
+        if keys.is_empty() {
+            return "No reaction to this message".to_string();
+        }
+        keys.iter()
+            .map(|(key, sources, _)| {
+                let names: Vec<_> = sources.iter().map(|s| s.name.as_str()).collect();
+                format!("{} {}", key, names.join(", "))
+            })
+            .collect::<Vec<_>>()
+            .join(" · ")
+    }
+
+    /// Add our reaction `key` to the selected message, or remove it if we already reacted so.
+    pub fn toggle_reaction(&self, key: String) -> Result<ActionKind, String> {
+        let message = match self.widget.selected().map(|i| &self.events[i].event) {
+            Some(NetEventKind::Message(m)) => m,
+            Some(_) => return Err("Only the messages of the server can be reacted to".to_string()),
+            None => return Err("Select a message first (j/k)".to_string()),
+        };
+        let id = match (&message.kind, &message.id) {
+            (MessageKind::Redacted(_), _) | (_, None) => {
+                return Err("Cannot react to this message".to_string())
+            }
+            (_, Some(id)) => id,
+        };
+        let own = self
+            .reactions
+            .get(id)
+            .into_iter()
+            .flatten()
+            .find(|(_, r)| r.own && r.key == key);
+        Ok(match own {
+            Some((_, reaction)) => ActionKind::Redact {
+                id: reaction.id.clone(),
+                reason: None,
+            },
+            None => ActionKind::React {
+                id: id.clone(),
+                key,
+            },
+        })
+    }
+
+    /// Initials of the other users having read up to a message.
+    fn receipts_span(&self, id: &str) -> Option<Span> {
+        let mut names: Vec<_> = self
+            .receipts
+            .values()
+            .filter(|(_, event_id)| event_id == id)
+            .map(|(source, _)| source.name.as_str())
+            .collect();
+        if names.is_empty() {
+            return None;
+        }
+        names.sort_unstable();
+        let initials: Vec<String> = names
+            .iter()
+            .map(|name| {
+                let name = name.trim_start_matches('@');
+                name.chars().next().unwrap_or('?').to_uppercase().collect()
+            })
+            .collect();
+        Some(Span::new(
+            &format!("  ✓ {}", initials.join(" ")),
+            QUOTE_STYLE,
+        ))
+    }
+
+    fn set_receipt(&mut self, source: Source, id: String) {
+        let previous = self
+            .receipts
+            .insert(source.id.clone(), (source, id.clone()));
+        if let Some((_, previous)) = previous {
+            self.rebuild_message(&previous);
+        }
+        self.rebuild_message(&id);
+    }
+
+    /// ID of the latest message received from the server.
+    fn last_message_id(&self) -> Option<&String> {
+        self.events.iter().rev().find_map(|ev| match &ev.event {
+            NetEventKind::Message(Message { id: Some(id), .. }) => Some(id),
+            _ => None,
+        })
+    }
+
+    fn set_fully_read(&mut self, id: String) {
+        // Show where the unread messages of a displayed room start, if we do not know it yet
+        if self.viewed && self.divider.is_none() && self.last_message_id() != Some(&id) {
+            self.divider = Some(id.clone());
+            self.rebuild_message(&id);
+        }
+        self.fully_read = Some(id);
+    }
+
+    /// Tell whether the room is displayed, the new messages divider being shown meanwhile.
+    pub fn set_viewed(&mut self, viewed: bool) {
+        if self.viewed == viewed {
+            return;
+        }
+        self.viewed = viewed;
+        if let Some(previous) = self.divider.take() {
+            self.rebuild_message(&previous);
+        }
+        if viewed && self.fully_read.as_ref() != self.last_message_id() {
+            self.divider = self.fully_read.clone();
+            if let Some(divider) = self.divider.clone() {
+                self.rebuild_message(&divider);
+            }
+        }
+    }
+
+    /// Move our read marker to the latest message of the displayed room.
+    pub fn mark_read(&mut self) -> Vec<Action> {
+        if !self.viewed {
+            return vec![];
+        }
+        self.unread = Unread::default();
+        let id = match self.last_message_id() {
+            Some(id) if self.fully_read.as_ref() != Some(id) => id.clone(),
+            _ => return vec![],
+        };
+        self.fully_read = Some(id.clone());
+        vec![Action::Room(RoomAction::MarkRead { room: self.id, id })]
+    }
+
+    fn editable(message: &Message) -> bool {
+        let kind = matches!(
+            message.kind,
+            MessageKind::Text | MessageKind::Emote | MessageKind::Notice
+        );
+        kind && message.own && message.id.is_some()
+    }
+
+    /// Latest message of the local user that can be edited.
+    pub fn last_own_message(&self) -> Option<&Message> {
+        self.events.iter().rev().find_map(|ev| match &ev.event {
+            NetEventKind::Message(m) if Self::editable(m) => Some(m),
+            _ => None,
+        })
+    }
+
+    /// Message to edit: the selected one, or else the latest one of the local user.
+    fn message_to_edit(&self) -> Result<&Message, String> {
+        match self.widget.selected().map(|i| &self.events[i].event) {
+            Some(NetEventKind::Message(m)) if Self::editable(m) => Ok(m),
+            Some(_) => Err("Only your own text messages can be edited".to_string()),
+            None => self
+                .last_own_message()
+                .ok_or_else(|| "No message of yours to edit in this room".to_string()),
+        }
+    }
+
+    /// ID of the selected message, to be deleted.
+    pub fn selected_message_id(&self) -> Result<String, String> {
+        let message = match self.widget.selected().map(|i| &self.events[i].event) {
+            Some(NetEventKind::Message(m)) => m,
+            Some(_) => return Err("Only the messages of the server can be deleted".to_string()),
+            None => return Err("Select a message first (j/k)".to_string()),
+        };
+        match (&message.kind, &message.id) {
+            (MessageKind::Redacted(_), _) => Err("This message is already deleted".to_string()),
+            (_, Some(id)) => Ok(id.clone()),
+            (_, None) => Err("Only the messages of the server can be deleted".to_string()),
+        }
+    }
+
+    /// Index of the local echo a message of the server replaces.
+    fn echo_index(&self, message: &Message) -> Option<usize> {
+        self.events.iter().position(|ev| match &ev.event {
+            NetEventKind::Message(m) if m.delivery.is_some() => {
+                (m.txn_id.is_some() && m.txn_id == message.txn_id)
+                    || (m.id.is_some() && m.id == message.id)
+            }
+            _ => false,
+        })
+    }
+
+    fn echo_index_by_txn(&self, txn_id: &str) -> Option<usize> {
+        let txn_id = Some(txn_id.to_string());
+        self.events.iter().position(|ev| match &ev.event {
+            NetEventKind::Message(m) => m.delivery.is_some() && m.txn_id == txn_id,
+            _ => false,
+        })
+    }
+
+    fn remove_event(&mut self, i: usize) {
+        self.events.remove(i);
+        self.widget.remove(i);
+    }
+
+    pub fn remove_echo(&mut self, txn_id: &str) {
+        if let Some(i) = self.echo_index_by_txn(txn_id) {
+            self.remove_event(i);
+        }
+    }
+
+    /// Update the delivery state of a local echo, unless the server already sent it back.
+    fn set_delivery(&mut self, update: DeliveryUpdate) {
+        let i = match self.echo_index_by_txn(&update.txn_id) {
+            Some(i) => i,
+            None => return,
+        };
+        if let NetEventKind::Message(m) = &mut self.events[i].event {
+            m.delivery = Some(update.delivery);
+            if update.id.is_some() {
+                m.id = update.id;
+            }
+        }
+        let widget = self.build_entry(&self.events[i]);
+        self.widget.replace(i, widget);
+    }
+
+    /// Transaction ID of the selected echo, or else of the latest one, in one of the `states`.
+    fn find_echo(&self, states: &[Delivery]) -> Option<Result<String, ()>> {
+        let find = |ev: &NetEvent| match &ev.event {
+            NetEventKind::Message(Message {
+                delivery: Some(delivery),
+                txn_id: Some(txn_id),
+                ..
+            }) if states.contains(delivery) => Some(txn_id.clone()),
+            _ => None,
+        };
+        match self.widget.selected() {
+            Some(i) => Some(find(&self.events[i]).ok_or(())),
+            None => self.events.iter().rev().find_map(find).map(Ok),
+        }
+    }
+
+    /// Failed message to send again.
+    pub fn failed_message(&self) -> Result<String, String> {
+        match self.find_echo(&[Delivery::Failed]) {
+            Some(Ok(txn_id)) => Ok(txn_id),
+            Some(Err(())) => Err("This message did not fail".to_string()),
+            None => Err("No failed message in this room".to_string()),
+        }
+    }
+
+    /// Message not sent yet, to cancel.
+    pub fn unsent_message(&self) -> Result<String, String> {
+        match self.find_echo(&[Delivery::Queued, Delivery::Failed]) {
+            Some(Ok(txn_id)) => Ok(txn_id),
+            Some(Err(())) => Err("This message is not waiting to be sent".to_string()),
+            None => Err("No message waiting to be sent in this room".to_string()),
+        }
+    }
+
+    /// Selected message not sent yet, whose content can still be changed.
+    fn unsent_to_edit(&self) -> Option<&Message> {
+        match self.widget.selected().map(|i| &self.events[i].event) {
+            Some(NetEventKind::Message(m)) => match m.delivery {
+                Some(Delivery::Queued) | Some(Delivery::Failed) => Some(m),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// Content URI and name of the attachment of the selected message.
+    pub fn selected_attachment(&self) -> Result<(String, String), String> {
+        match self.widget.selected().map(|i| &self.events[i].event) {
+            Some(NetEventKind::Message(Message {
+                kind: MessageKind::Attachment(attachment),
+                ..
+            })) => match attachment.url.as_ref() {
+                Some(url) => Ok((url.clone(), attachment.name.clone())),
+                None => Err("This attachment cannot be downloaded".to_string()),
+            },
+            Some(_) => Err("This message has no attachment".to_string()),
+            None => Err("Select a message first (j/k)".to_string()),
+        }
+    }
+
+    /// Move the selection by `offset` entries, starting from the last one.
+    fn move_selection(&mut self, offset: isize) {
+        if self.widget.is_empty() {
+            return;
+        }
+        let last = self.widget.len() - 1;
+        let selected = match self.widget.selected() {
+            Some(i) => (i as isize + offset).max(0).min(last as isize) as usize,
+            None => last,
+        };
+        self.widget.select(Some(selected));
+    }
+
+    /// Who is typing, for the status line.
+    pub fn typing_line(&self) -> Option<String> {
+        let names: Vec<_> = self.typing.iter().map(|s| s.name.as_str()).collect();
+        let line = match names.as_slice() {
+            [] => return None,
+            [name] => format!("{} is typing…", name),
+            [names @ .., last] if names.len() < 3 => {
+                format!("{} and {} are typing…", names.join(", "), last)
+            }
+            [first, second, others @ ..] => format!(
+                "{}, {} and {} others are typing…",
+                first,
+                second,
+                others.len()
+            ),
+        };
+        Some(line)
+    }
+
+    /// Name of the room in the room list.
+    pub fn list_label(&self) -> String {
+        match &self.invite {
+            Some(Invite {
+                inviter: Some(inviter),
+            }) => format!("[invite] {} (from {})", self.conf.alias, inviter.name),
+            Some(_) => format!("[invite] {}", self.conf.alias),
+            None => match self.unread {
+                Unread {
+                    notifications: 0, ..
+                } => self.conf.alias.clone(),
+                Unread {
+                    notifications,
+                    highlights: 0,
+                } => format!("{} ({})", self.conf.alias, notifications),
+                Unread {
+                    notifications,
+                    highlights,
+                } => format!("{} ({}, !{})", self.conf.alias, notifications, highlights),
+            },
+        }
+    }
+
+    /// Joined and invited members, by decreasing power level and then by name.
+    pub fn sorted_members(&self) -> Vec<&Member> {
+        let mut members: Vec<&Member> = self
+            .members
+            .values()
+            .filter(|m| {
+                matches!(
+                    m.membership,
+                    MatrixMembership::Join | MatrixMembership::Invite
+                )
+            })
+            .collect();
+        members.sort_by(|a, b| {
+            b.power_level.cmp(&a.power_level).then_with(|| {
+                a.source
+                    .name
+                    .to_lowercase()
+                    .cmp(&b.source.name.to_lowercase())
+            })
+        });
+        members
+    }
+
+    fn set_state(&mut self, change: StateChange) {
+        let StateChange { key, value } = change;
+        if key == STATE_NAME {
+            if let Some(name) = value.as_ref() {
+                self.conf.alias = name.clone();
+            }
+        }
+        match value {
+            Some(value) => self.state.insert(key, value),
+            None => self.state.remove(&key),
+        };
+    }
 }
 
 impl tui::widgets::Widget for Room {
     fn draw(&mut self, area: tui::layout::Rect, buf: &mut tui::buffer::Buffer) {
-        self.widget.draw(area, buf);
+        let topic = match self.state.get(STATE_TOPIC) {
+            Some(topic) if area.height > 1 => topic,
+            _ => return self.widget.draw(area, buf),
+        };
+
+        let layout = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([Constraint::Length(1), Constraint::Min(1)].as_ref())
+            .split(area);
+        // Only the first line of the topic fits in the header
+        let topic = topic.lines().next().unwrap_or("");
+        Paragraph::new(
+            [Text::styled(
+                topic,
+                Style::default().modifier(Modifier::BOLD),
+            )]
+            .iter(),
+        )
+        .draw(layout[0], buf);
+        self.widget.draw(layout[1], buf);
     }
 }
 
@@ -73,8 +939,79 @@ impl EventProcessor for Room {
     fn process_event(&mut self, event: Event) -> Vec<Action> {
         match event {
             Event::Key(k) => match k {
-                Key::Up => self.widget.up(),
+                Key::Up => {
+                    // Scrolling past the top asks for older events
+                    if self.widget.is_at_top() && !self.history_pending {
+                        self.history_pending = true;
+                        self.widget.up();
+                        return vec![Action::Room(RoomAction::LoadHistory)];
+                    }
+                    self.widget.up()
+                }
                 Key::Down => self.widget.down(),
+                Key::Char('k') => self.move_selection(-1),
+                Key::Char('j') => self.move_selection(1),
+                Key::Char('v') => {
+                    return vec![Action::App(AppAction::StatusSet(self.selected_reactions()))]
+                }
+                Key::Char('r') => match self.selected_reply() {
+                    Ok(reply) => {
+                        let name = self.events[self.widget.selected().unwrap()]
+                            .source
+                            .as_ref()
+                            .map_or(reply.sender.clone(), |s| s.name.clone());
+                        self.reply = Some(reply);
+                        self.editing = None;
+                        self.editing_unsent = None;
+                        self.focused = false;
+                        return vec![
+                            Action::App(AppAction::StatusSet(format!("Replying to {}", name))),
+                            Action::App(AppAction::FocusInput),
+                        ];
+                    }
+                    Err(e) => return vec![Action::App(AppAction::StatusSet(e))],
+                },
+                Key::Char('e') if self.unsent_to_edit().is_some() => {
+                    let message = self.unsent_to_edit().unwrap();
+                    let content = message.content.clone();
+                    self.editing_unsent = message.txn_id.clone();
+                    self.editing = None;
+                    self.reply = None;
+                    self.focused = false;
+                    return vec![
+                        Action::App(AppAction::FocusInput),
+                        Action::App(AppAction::InputSet(content)),
+                        Action::App(AppAction::StatusSet("Editing unsent message".to_string())),
+                    ];
+                }
+                Key::Char('e') => match self.message_to_edit() {
+                    Ok(message) => {
+                        let content = message.content.clone();
+                        self.editing = message.id.clone();
+                        self.editing_unsent = None;
+                        self.reply = None;
+                        self.focused = false;
+                        return vec![
+                            Action::App(AppAction::FocusInput),
+                            Action::App(AppAction::InputSet(content)),
+                            Action::App(AppAction::StatusSet("Editing message".to_string())),
+                        ];
+                    }
+                    Err(e) => return vec![Action::App(AppAction::StatusSet(e))],
+                },
+                // Cancel the selection and the pending reply or edit before leaving the room
+                Key::Esc
+                    if self.widget.selected().is_some()
+                        || self.reply.is_some()
+                        || self.editing.is_some()
+                        || self.editing_unsent.is_some() =>
+                {
+                    self.widget.select(None);
+                    self.reply = None;
+                    self.editing = None;
+                    self.editing_unsent = None;
+                    return vec![Action::App(AppAction::StatusSet(String::new()))];
+                }
                 Key::Esc => {
                     self.focused = false;
                     return vec![Action::FocusLoss];
@@ -82,26 +1019,135 @@ impl EventProcessor for Room {
                 _ => (),
             },
             Event::Mouse(_) => (),
+            Event::Net(NetEvent {
+                event: NetEventKind::HistoryLoaded,
+                ..
+            }) => self.history_pending = false,
+            Event::Net(NetEvent {
+                event: NetEventKind::Rename(source),
+                ..
+            }) => self.rename(source),
+            Event::Net(NetEvent {
+                event: NetEventKind::State(change),
+                ..
+            }) => self.set_state(change),
+            Event::Net(NetEvent {
+                event: NetEventKind::Member(member),
+                ..
+            }) => {
+                self.members.insert(member.source.id.clone(), member);
+            }
+            Event::Net(
+                ev @ NetEvent {
+                    event: NetEventKind::Edit(_),
+                    ..
+                },
+            )
+            | Event::Net(
+                ev @ NetEvent {
+                    event: NetEventKind::Redaction(_),
+                    ..
+                },
+            ) => self.change_message(ev),
+            Event::Net(NetEvent {
+                event: NetEventKind::Receipt(id),
+                source: Some(source),
+                ..
+            }) => self.set_receipt(source, id),
+            Event::Net(NetEvent {
+                event: NetEventKind::FullyRead(id),
+                ..
+            }) => self.set_fully_read(id),
+            Event::Net(NetEvent {
+                event: NetEventKind::Typing(sources),
+                ..
+            }) => self.typing = sources,
+            Event::Net(NetEvent {
+                event: NetEventKind::Delivery(update),
+                ..
+            }) => self.set_delivery(update),
+            Event::Net(NetEvent {
+                event: NetEventKind::Unread(unread),
+                ..
+            }) => {
+                // The server may not know yet that we read the displayed room
+                if !self.viewed {
+                    self.unread = unread;
+                }
+            }
+            Event::Net(NetEvent {
+                event: NetEventKind::Reaction(reaction),
+                source: Some(source),
+                ..
+            }) => self.add_reaction(source, reaction),
+            Event::Net(
+                ev @ NetEvent {
+                    event: NetEventKind::Referenced(_),
+                    ..
+                },
+            ) => {
+                if let NetEventKind::Referenced(Message { id: Some(id), .. }) = &ev.event {
+                    let id = id.clone();
+                    self.referenced.insert(id.clone(), ev);
+                    self.rebuild_replies(&id);
+                }
+            }
             Event::Net(ev) => {
-                // TODO Process events as content editing entries
-                let text = ev.event.to_string();
-
-                let widget = Box::new(RoomEntry::new(
-                    room_entry::Meta {
-                        date: ev.date,
-                        sender: ev.source.clone(),
-                    },
-                    &text,
-                    room_entry::Conf {
-                        meta_width: self.conf.meta_width,
-                    },
-                ));
+                match &ev.event {
+                    NetEventKind::Invite(invite) => self.invite = Some(invite.clone()),
+                    NetEventKind::Connected | NetEventKind::Disconnected => self.invite = None,
+                    // The server sent back a message we echoed
+                    NetEventKind::Message(m) if m.delivery.is_none() => {
+                        if let Some(i) = self.echo_index(m) {
+                            self.remove_event(i);
+                        } else if let Some(id) = &m.id {
+                            // Gaps and history pages may overlap what we already have
+                            if self.message_index(id).is_some() {
+                                return vec![];
+                            }
+                        }
+                    }
+                    // New content of a message not sent yet, keeping its place
+                    NetEventKind::Message(Message {
+                        txn_id: Some(txn_id),
+                        ..
+                    }) => {
+                        if let Some(i) = self.echo_index_by_txn(txn_id) {
+                            self.events[i].event = ev.event;
+                            let widget = self.build_entry(&self.events[i]);
+                            self.widget.replace(i, widget);
+                            return vec![];
+                        }
+                    }
+                    _ => (),
+                }
+
+                let widget = self.build_entry(&ev);
+
+                // Keep the timeline sorted by date as history events arrive late
+                let index = self
+                    .events
+                    .iter()
+                    .rposition(|e| e.date <= ev.date)
+                    .map_or(0, |i| i + 1);
 
                 // TODO Rebuild the full UI
-                self.widget.push(widget);
+                self.widget.insert(index, widget);
+
+                // Quote replied messages, fetching them if needed
+                let actions = self.request_parent(&ev);
+                let id = match &ev.event {
+                    NetEventKind::Message(Message { id: Some(id), .. }) => Some(id.clone()),
+                    _ => None,
+                };
 
                 // Save the event
-                self.events.push(ev);
+                self.events.insert(index, ev);
+                if let Some(id) = id {
+                    self.apply_pending_changes(index, &id);
+                    self.rebuild_replies(&id);
+                }
+                return actions;
             }
         };
         vec![]
@@ -0,0 +1,58 @@
+//! Emoji shortcodes, like `:thumbsup:`.
+
+const SHORTCODES: &[(&str, &str)] = &[
+    ("+1", "👍"),
+    ("-1", "👎"),
+    ("100", "💯"),
+    ("angry", "😠"),
+    ("broken_heart", "💔"),
+    ("check", "✔️"),
+    ("clap", "👏"),
+    ("confused", "😕"),
+    ("cry", "😢"),
+    ("eyes", "👀"),
+    ("fire", "🔥"),
+    ("grin", "😁"),
+    ("heart", "❤️"),
+    ("heart_eyes", "😍"),
+    ("joy", "😂"),
+    ("laughing", "😆"),
+    ("ok_hand", "👌"),
+    ("party", "🎉"),
+    ("pray", "🙏"),
+    ("raised_hands", "🙌"),
+    ("rocket", "🚀"),
+    ("scream", "😱"),
+    ("smile", "😄"),
+    ("slightly_smiling_face", "🙂"),
+    ("sob", "😭"),
+    ("star", "⭐"),
+    ("tada", "🎉"),
+    ("thinking", "🤔"),
+    ("thumbsdown", "👎"),
+    ("thumbsup", "👍"),
+    ("wave", "👋"),
+    ("wink", "😉"),
+    ("x", "❌"),
+];
+
+/// Emoji of a shortcode, given without its colons.
+pub fn shortcode(name: &str) -> Option<&'static str> {
+    SHORTCODES
+        .iter()
+        .find(|(code, _)| *code == name)
+        .map(|(_, emoji)| *emoji)
+}
+
+/// Reaction key typed by the user: either the key itself or the `:shortcode:` of an emoji.
+pub fn reaction_key(text: &str) -> Result<String, String> {
+    let text = text.trim();
+    match text.strip_prefix(':').and_then(|s| s.strip_suffix(':')) {
+        Some(name) if !name.is_empty() => match shortcode(name) {
+            Some(emoji) => Ok(emoji.to_string()),
+            None => Err(format!("Unknown emoji shortcode '{}'", text)),
+        },
+        _ if text.is_empty() => Err("Empty reaction".to_string()),
+        _ => Ok(text.to_string()),
+    }
+}
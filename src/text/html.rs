@@ -0,0 +1,628 @@
+//! Sanitising renderer of the HTML subset allowed in Matrix messages (`org.matrix.custom.html`).
+//!
+//! Unknown tags are dropped but their text is kept, except for the tags whose content must never
+//! be shown (scripts, reply fallbacks, ...).
+
+use super::styled_text::{Span, StyledText};
+use tui::style::{Color, Modifier, Style};
+
+const CODE_STYLE: Style = Style {
+    fg: Color::Yellow,
+    bg: Color::Reset,
+    modifier: Modifier::empty(),
+};
+
+const LINK_STYLE: Style = Style {
+    fg: Color::Blue,
+    bg: Color::Reset,
+    modifier: Modifier::UNDERLINED,
+};
+
+const PILL_STYLE: Style = Style {
+    fg: Color::Black,
+    bg: Color::Blue,
+    modifier: Modifier::empty(),
+};
+
+const QUOTE_STYLE: Style = Style {
+    fg: Color::DarkGray,
+    bg: Color::Reset,
+    modifier: Modifier::empty(),
+};
+
+const QUOTE_PREFIX: &str = "│ ";
+const LIST_BULLET: &str = "• ";
+const RULE: &str = "────────";
+
+// Tags dropped along with their content
+const HIDDEN_TAGS: [&str; 6] = ["head", "mx-reply", "script", "style", "title", "template"];
+// Tags without content nor closing tag
+const VOID_TAGS: [&str; 6] = ["br", "hr", "img", "input", "meta", "wbr"];
+// Tags laid out on their own lines
+const BLOCK_TAGS: [&str; 18] = [
+    "blockquote",
+    "caption",
+    "div",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "li",
+    "ol",
+    "p",
+    "pre",
+    "table",
+    "tbody",
+    "thead",
+    "tr",
+    "ul",
+];
+const LINK_SCHEMES: [&str; 6] = ["http:", "https:", "ftp:", "mailto:", "magnet:", "matrix:"];
+const MATRIX_TO_PREFIX: &str = "https://matrix.to/#/";
+
+/// Escape the characters of a text that have a meaning in HTML.
+pub fn escape(text: &str) -> String {
+    let mut ret = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => ret.push_str("&amp;"),
+            '<' => ret.push_str("&lt;"),
+            '>' => ret.push_str("&gt;"),
+            '"' => ret.push_str("&quot;"),
+            c => ret.push(c),
+        }
+    }
+    ret
+}
+
+// =============================================================================
+// Tokens
+// =============================================================================
+#[derive(Debug)]
+struct Tag {
+    name: String,
+    attributes: Vec<(String, String)>,
+    closing: bool,
+    self_closing: bool,
+}
+
+impl Tag {
+    fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+#[derive(Debug)]
+enum Token {
+    Text(String),
+    Tag(Tag),
+}
+
+fn decode_entity(entity: &str) -> Option<char> {
+    match entity {
+        "amp" => Some('&'),
+        "lt" => Some('<'),
+        "gt" => Some('>'),
+        "quot" => Some('"'),
+        "apos" => Some('\''),
+        "nbsp" => Some('\u{a0}'),
+        _ if entity.starts_with("#x") || entity.starts_with("#X") => {
+            u32::from_str_radix(&entity[2..], 16)
+                .ok()
+                .and_then(std::char::from_u32)
+        }
+        _ if entity.starts_with('#') => entity[1..].parse().ok().and_then(std::char::from_u32),
+        _ => None,
+    }
+}
+
+/// Replace the character references of a text.
+fn decode(text: &str) -> String {
+    let mut ret = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        ret.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let decoded = rest
+            .find(';')
+            .filter(|end| *end <= 10)
+            .and_then(|end| decode_entity(&rest[1..end]).map(|c| (c, end)));
+        match decoded {
+            Some((c, end)) => {
+                ret.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                ret.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    ret.push_str(rest);
+    ret
+}
+
+fn parse_tag(tag: &str) -> Option<Tag> {
+    let closing = tag.starts_with('/');
+    let tag = tag.trim_start_matches('/');
+    let self_closing = tag.ends_with('/');
+    let tag = tag.trim_end_matches('/');
+
+    let name_end = tag.find(|c: char| c.is_whitespace()).unwrap_or(tag.len());
+    let name = tag[..name_end].to_lowercase();
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+        return None;
+    }
+
+    // Attributes: name, name=value, name="value" or name='value'
+    let mut attributes = vec![];
+    let mut chars = tag[name_end..].chars().peekable();
+    loop {
+        while chars.peek().is_some_and(|c| c.is_whitespace()) {
+            chars.next();
+        }
+        let mut attr = String::new();
+        while let Some(c) = chars.peek() {
+            if c.is_whitespace() || *c == '=' {
+                break;
+            }
+            attr.push(*c);
+            chars.next();
+        }
+        if attr.is_empty() {
+            break;
+        }
+        let mut value = String::new();
+        if chars.peek() == Some(&'=') {
+            chars.next();
+            match chars.peek() {
+                Some(&quote) if quote == '"' || quote == '\'' => {
+                    chars.next();
+                    value = chars.by_ref().take_while(|c| *c != quote).collect();
+                }
+                _ => {
+                    while let Some(c) = chars.peek() {
+                        if c.is_whitespace() {
+                            break;
+                        }
+                        value.push(*c);
+                        chars.next();
+                    }
+                }
+            }
+        }
+        attributes.push((attr.to_lowercase(), decode(&value)));
+    }
+
+    Some(Tag {
+        name,
+        attributes,
+        closing,
+        self_closing,
+    })
+}
+
+fn tokenize(html: &str) -> Vec<Token> {
+    let mut tokens = vec![];
+    let mut rest = html;
+    while !rest.is_empty() {
+        if rest.starts_with("<!--") {
+            rest = match rest.find("-->") {
+                Some(end) => &rest[end + 3..],
+                None => "",
+            };
+            continue;
+        }
+        if rest.starts_with('<') {
+            // Find the end of the tag, ignoring the '>' of quoted attribute values
+            let mut quote = None;
+            let end = rest.char_indices().skip(1).find(|(_, c)| match quote {
+                Some(q) if *c == q => {
+                    quote = None;
+                    false
+                }
+                Some(_) => false,
+                None if *c == '"' || *c == '\'' => {
+                    quote = Some(*c);
+                    false
+                }
+                None => *c == '>',
+            });
+            if let Some((end, _)) = end {
+                match parse_tag(&rest[1..end]) {
+                    Some(tag) => tokens.push(Token::Tag(tag)),
+                    // Declarations, processing instructions, ...
+                    None if rest[1..].starts_with(&['!', '?', '/'][..]) => (),
+                    // Not a tag, only keep the '<' as text
+                    None => {
+                        tokens.push(Token::Text("<".to_string()));
+                        rest = &rest[1..];
+                        continue;
+                    }
+                }
+                rest = &rest[end + 1..];
+                continue;
+            }
+        }
+        // Text up to the next tag, keeping a '<' that does not start a tag
+        let first = rest.chars().next().map_or(1, char::len_utf8);
+        let end = rest[first..].find('<').map_or(rest.len(), |i| i + first);
+        tokens.push(Token::Text(decode(&rest[..end])));
+        rest = &rest[end..];
+    }
+    tokens
+}
+
+// =============================================================================
+// Rendering
+// =============================================================================
+fn color(value: &str) -> Option<Color> {
+    let hex = value.trim().trim_start_matches('#');
+    if hex.len() != 6 {
+        return None;
+    }
+    let channel = |i: usize| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok();
+    Some(Color::Rgb(channel(0)?, channel(2)?, channel(4)?))
+}
+
+fn safe_link(href: &str) -> bool {
+    let href = href.trim().to_lowercase();
+    LINK_SCHEMES.iter().any(|scheme| href.starts_with(scheme))
+}
+
+/// Whether a link designates a user, a room or a group, rather than a web page.
+fn is_pill(href: &str) -> bool {
+    if href.starts_with("matrix:") {
+        return ["matrix:u/", "matrix:r/", "matrix:roomid/"]
+            .iter()
+            .any(|p| href.starts_with(p));
+    }
+    let target = match href.strip_prefix(MATRIX_TO_PREFIX) {
+        Some(target) => target,
+        None => return false,
+    };
+    target.starts_with(&['@', '#', '!', '+'][..])
+        || target.starts_with("%40")
+        || target.starts_with("%23")
+}
+
+#[derive(Debug)]
+struct Element {
+    name: String,
+    style: Style,
+    // Prefix of the lines started inside of the element
+    prefix: Option<Span>,
+    // Text to append when the element is closed
+    suffix: Option<Span>,
+}
+
+#[derive(Debug, Default)]
+struct Renderer {
+    text: StyledText,
+    line: Vec<Span>,
+    // Whether the current line holds something else than its prefix
+    line_started: bool,
+    elements: Vec<Element>,
+    // Counters of the open lists, `None` for unordered ones
+    lists: Vec<Option<u64>>,
+    hidden: usize,
+    preformatted: usize,
+    // Whitespace is collapsed to a single space, written before the next word
+    pending_space: bool,
+}
+
+impl Renderer {
+    fn style(&self) -> Style {
+        self.elements.iter().fold(Style::default(), |style, e| {
+            super::styled_text::patch(style, e.style)
+        })
+    }
+
+    fn push(&mut self, s: &str, style: Style) {
+        if !self.line_started {
+            let prefix: Vec<Span> = self
+                .elements
+                .iter()
+                .filter_map(|e| e.prefix.clone())
+                .collect();
+            self.line.extend(prefix);
+            self.line_started = true;
+        }
+        match self.line.last_mut() {
+            Some(last) if last.style == style => last.s.push_str(s),
+            _ => self.line.push(Span::new(s, style)),
+        }
+    }
+
+    fn new_line(&mut self) {
+        let line = std::mem::take(&mut self.line);
+        self.text.lines.push(line);
+        self.line_started = false;
+        self.pending_space = false;
+    }
+
+    /// End the current line, if anything was written on it.
+    fn end_block(&mut self) {
+        if self.line_started {
+            self.new_line();
+        }
+    }
+
+    fn text(&mut self, text: &str) {
+        if self.hidden > 0 {
+            return;
+        }
+        let style = self.style();
+        if self.preformatted > 0 {
+            for (i, line) in text.split('\n').enumerate() {
+                if i > 0 {
+                    self.new_line();
+                }
+                if !line.is_empty() {
+                    self.push(&line.replace('\t', "    "), style);
+                }
+            }
+            return;
+        }
+        for (i, word) in text.split(|c: char| c.is_ascii_whitespace()).enumerate() {
+            if i > 0 {
+                self.pending_space = true;
+            }
+            if word.is_empty() {
+                continue;
+            }
+            if self.pending_space && self.line_started {
+                // Do not extend the style of the next word (links, pills, ...) to the space
+                let space_style = self.line.last().map_or(style, |span| span.style);
+                self.push(" ", space_style);
+            }
+            self.pending_space = false;
+            self.push(word, style);
+        }
+    }
+
+    fn open(&mut self, tag: &Tag) {
+        if HIDDEN_TAGS.contains(&tag.name.as_str()) && !tag.self_closing {
+            self.hidden += 1;
+        }
+        if self.hidden > 0 {
+            if !VOID_TAGS.contains(&tag.name.as_str()) && !tag.self_closing {
+                self.elements.push(Element {
+                    name: tag.name.clone(),
+                    style: Style::default(),
+                    prefix: None,
+                    suffix: None,
+                });
+            }
+            return;
+        }
+        if BLOCK_TAGS.contains(&tag.name.as_str()) {
+            self.end_block();
+        }
+
+        // Only the elements pushed get closed, restoring the state they change
+        let pushed = !VOID_TAGS.contains(&tag.name.as_str()) && !tag.self_closing;
+        let mut element = Element {
+            name: tag.name.clone(),
+            style: Style::default(),
+            prefix: None,
+            suffix: None,
+        };
+        match tag.name.as_str() {
+            "br" => self.new_line(),
+            "hr" => {
+                self.end_block();
+                self.push(RULE, QUOTE_STYLE);
+                self.new_line();
+            }
+            "img" => {
+                let alt = tag.attribute("alt").or_else(|| tag.attribute("title"));
+                let alt = format!("[{}]", alt.unwrap_or("image"));
+                self.text(&alt);
+            }
+            "b" | "strong" | "h1" | "h2" | "h3" | "h4" | "h5" | "h6" | "th" => {
+                element.style = element.style.modifier(Modifier::BOLD)
+            }
+            "i" | "em" | "cite" => element.style = element.style.modifier(Modifier::ITALIC),
+            "u" | "ins" => element.style = element.style.modifier(Modifier::UNDERLINED),
+            "del" | "s" | "strike" => element.style = element.style.modifier(Modifier::CROSSED_OUT),
+            "code" => element.style = CODE_STYLE,
+            "pre" => {
+                element.style = CODE_STYLE;
+                if pushed {
+                    self.preformatted += 1;
+                }
+            }
+            "blockquote" => {
+                element.style = QUOTE_STYLE;
+                element.prefix = Some(Span::new(QUOTE_PREFIX, QUOTE_STYLE));
+            }
+            "ul" if pushed => self.lists.push(None),
+            "ol" if pushed => {
+                let start = tag.attribute("start").and_then(|s| s.parse().ok());
+                self.lists.push(Some(start.unwrap_or(1)));
+            }
+            "li" => {
+                let marker = match self.lists.last_mut() {
+                    Some(Some(n)) => {
+                        *n += 1;
+                        format!("{}. ", *n - 1)
+                    }
+                    _ => LIST_BULLET.to_string(),
+                };
+                let style = self.style();
+                self.push(&marker, style);
+                // Align the next lines of the item on its text
+                let indent = " ".repeat(Span::new(&marker, style).width());
+                element.prefix = Some(Span::new(&indent, Style::default()));
+            }
+            "td" if self.line_started => self.push("  ", Style::default()),
+            "a" => match tag.attribute("href") {
+                Some(href) if is_pill(href) => element.style = PILL_STYLE,
+                Some(href) if safe_link(href) => {
+                    element.style = LINK_STYLE;
+                    element.suffix = Some(Span::new(href, LINK_STYLE));
+                }
+                _ => (),
+            },
+            "font" | "span" => {
+                if let Some(fg) = tag
+                    .attribute("data-mx-color")
+                    .or_else(|| tag.attribute("color"))
+                    .and_then(color)
+                {
+                    element.style = element.style.fg(fg);
+                }
+                if let Some(bg) = tag.attribute("data-mx-bg-color").and_then(color) {
+                    element.style = element.style.bg(bg);
+                }
+            }
+            _ => (),
+        }
+
+        if pushed {
+            self.elements.push(element);
+        }
+    }
+
+    fn close(&mut self, tag: &Tag) {
+        // Ignore closing tags that were never opened
+        let i = match self.elements.iter().rposition(|e| e.name == tag.name) {
+            Some(i) => i,
+            None => return,
+        };
+        // Also close the elements left open inside of it
+        while self.elements.len() > i {
+            let element = self.elements.pop().unwrap();
+            self.close_element(element);
+        }
+    }
+
+    fn close_element(&mut self, element: Element) {
+        let name = element.name.as_str();
+        if self.hidden > 0 {
+            if HIDDEN_TAGS.contains(&name) {
+                self.hidden -= 1;
+            }
+            return;
+        }
+        match name {
+            "pre" => self.preformatted -= 1,
+            "ul" | "ol" => {
+                self.lists.pop();
+            }
+            _ => (),
+        }
+        if let Some(suffix) = element.suffix {
+            // Only show the target of links that do not display it already
+            let shown = self.line.iter().any(|span| span.s.contains(&suffix.s));
+            if !shown {
+                self.push(" <", Style::default());
+                self.push(&suffix.s, suffix.style);
+                self.push(">", Style::default());
+            }
+        }
+        if BLOCK_TAGS.contains(&name) {
+            self.end_block();
+        }
+    }
+
+    fn finish(mut self) -> StyledText {
+        while let Some(element) = self.elements.pop() {
+            self.close_element(element);
+        }
+        self.end_block();
+        // Drop the blank lines at the end
+        while self
+            .text
+            .lines
+            .last()
+            .is_some_and(|l| l.iter().all(|span| span.s.trim().is_empty()))
+        {
+            self.text.lines.pop();
+        }
+        self.text
+    }
+}
+
+/// Render an HTML message body, dropping anything that is not part of the allowed subset.
+pub fn parse(html: &str) -> StyledText {
+    let mut renderer = Renderer::default();
+    for token in tokenize(html) {
+        match token {
+            Token::Text(text) => renderer.text(&text),
+            Token::Tag(tag) if tag.closing => renderer.close(&tag),
+            Token::Tag(tag) => renderer.open(&tag),
+        }
+    }
+    renderer.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse;
+
+    /// Text of each line, without the styles.
+    fn lines(html: &str) -> Vec<String> {
+        parse(html)
+            .lines
+            .iter()
+            .map(|line| line.iter().map(|span| span.s.as_str()).collect())
+            .collect()
+    }
+
+    #[test]
+    fn hidden_tags() {
+        assert_eq!(
+            lines("<mx-reply><blockquote>quoted</blockquote></mx-reply>answer"),
+            vec!["answer"]
+        );
+        assert_eq!(lines("a<script>alert(1)</script>b"), vec!["ab"]);
+        assert_eq!(lines("a<style>p { color: red }</style>b"), vec!["ab"]);
+    }
+
+    #[test]
+    fn self_closing_tags_leak_no_state() {
+        assert_eq!(lines("<pre/>a   b"), vec!["a b"]);
+        assert_eq!(lines("<ul/><li>a</li>"), lines("<li>a</li>"));
+        assert_eq!(lines("<ol/><li>a</li>"), lines("<li>a</li>"));
+        assert_eq!(lines("<script/>a"), vec!["a"]);
+    }
+
+    #[test]
+    fn preformatted_whitespace() {
+        assert_eq!(lines("<pre>a   b</pre>c   d"), vec!["a   b", "c d"]);
+    }
+
+    #[test]
+    fn unsafe_links() {
+        assert_eq!(
+            lines("<a href=\"https://example.org\">site</a>"),
+            vec!["site <https://example.org>"]
+        );
+        assert_eq!(
+            lines("<a href=\"javascript:alert(1)\">site</a>"),
+            vec!["site"]
+        );
+        assert_eq!(lines("<a href=\"data:text/html,x\">site</a>"), vec!["site"]);
+    }
+
+    #[test]
+    fn entities() {
+        assert_eq!(lines("&lt;b&gt; &amp; &quot;"), vec!["<b> & \""]);
+        assert_eq!(lines("&#65;&#x42;"), vec!["AB"]);
+    }
+
+    #[test]
+    fn nested_lists() {
+        assert_eq!(
+            lines("<ol start=\"3\"><li>a<ul><li>b</li></ul></li><li>c</li></ol>"),
+            vec!["3. a", "   • b", "4. c"]
+        );
+    }
+}
@@ -0,0 +1,530 @@
+//! Conversion of the CommonMark typed in the message input to the HTML subset of Matrix messages.
+//!
+//! Only the common constructs are supported: paragraphs, headings, fenced code blocks, block
+//! quotes, lists, thematic breaks, emphasis, strikethrough, code spans and links. Any HTML typed by
+//! the user is escaped.
+
+use super::html::escape;
+
+const FENCES: [&str; 2] = ["```", "~~~"];
+const LINK_SCHEMES: [&str; 5] = ["http:", "https:", "ftp:", "mailto:", "matrix:"];
+
+#[derive(Debug)]
+enum Block {
+    Paragraph(Vec<String>),
+    Heading(usize, String),
+    Code(Option<String>, Vec<String>),
+    Quote(Vec<Block>),
+    List {
+        start: Option<u64>,
+        items: Vec<Vec<Block>>,
+    },
+    Rule,
+}
+
+// =============================================================================
+// Blocks
+// =============================================================================
+/// Indentation of a line, in bytes. Only ASCII blanks count, so that it is a char boundary.
+fn indent(line: &str) -> usize {
+    line.len() - line.trim_start_matches([' ', '\t']).len()
+}
+
+fn fence(line: &str) -> Option<&'static str> {
+    let line = line.trim_start();
+    FENCES.iter().find(|f| line.starts_with(*f)).copied()
+}
+
+fn heading(line: &str) -> Option<(usize, &str)> {
+    let line = line.trim_start();
+    let level = line.chars().take_while(|c| *c == '#').count();
+    let rest = &line[level..];
+    if (1..=6).contains(&level) && (rest.is_empty() || rest.starts_with(' ')) {
+        Some((level, rest.trim().trim_end_matches('#').trim_end()))
+    } else {
+        None
+    }
+}
+
+fn is_rule(line: &str) -> bool {
+    let marks: Vec<char> = line.chars().filter(|c| !c.is_whitespace()).collect();
+    marks.len() >= 3 && ['-', '*', '_'].contains(&marks[0]) && marks.iter().all(|c| *c == marks[0])
+}
+
+/// List item marker of a line: the list start number (`None` for bullets) and the content offset.
+fn list_marker(line: &str) -> Option<(Option<u64>, usize)> {
+    let offset = indent(line);
+    let line = &line[offset..];
+    let digits = line.chars().take_while(char::is_ascii_digit).count();
+    let (start, marker_len) = if digits == 0 {
+        match line.chars().next() {
+            Some('-') | Some('*') | Some('+') => (None, 1),
+            _ => return None,
+        }
+    } else if digits <= 9 && line[digits..].starts_with(&['.', ')'][..]) {
+        (line[..digits].parse().ok(), digits + 1)
+    } else {
+        return None;
+    };
+    match line[marker_len..].chars().next() {
+        None => Some((start, offset + marker_len)),
+        Some(' ') => Some((start, offset + marker_len + 1)),
+        _ => None,
+    }
+}
+
+/// Whether a line starts a block that interrupts a paragraph.
+fn interrupts_paragraph(line: &str) -> bool {
+    fence(line).is_some()
+        || heading(line).is_some()
+        || line.trim_start().starts_with('>')
+        || is_rule(line)
+        || match list_marker(line) {
+            // Only lists starting at 1 may interrupt a paragraph
+            Some((start, offset)) => {
+                !line[offset..].trim().is_empty() && (start.is_none() || start == Some(1))
+            }
+            None => false,
+        }
+}
+
+fn parse_blocks(lines: &[&str]) -> Vec<Block> {
+    let mut blocks = vec![];
+    let mut i = 0;
+    while i < lines.len() {
+        let line = lines[i];
+        if line.trim().is_empty() {
+            i += 1;
+        } else if let Some(fence) = fence(line) {
+            let info = line.trim_start()[fence.len()..].trim();
+            let lang = info.split_whitespace().next().map(str::to_string);
+            let mut code = vec![];
+            i += 1;
+            while i < lines.len() && !lines[i].trim_start().starts_with(fence) {
+                code.push(lines[i].to_string());
+                i += 1;
+            }
+            // Skip the closing fence
+            i += 1;
+            blocks.push(Block::Code(lang, code));
+        } else if let Some((level, title)) = heading(line) {
+            blocks.push(Block::Heading(level, title.to_string()));
+            i += 1;
+        } else if is_rule(line) {
+            blocks.push(Block::Rule);
+            i += 1;
+        } else if line.trim_start().starts_with('>') {
+            let mut quoted = vec![];
+            while i < lines.len() && lines[i].trim_start().starts_with('>') {
+                let content = &lines[i].trim_start()[1..];
+                quoted.push(content.strip_prefix(' ').unwrap_or(content));
+                i += 1;
+            }
+            blocks.push(Block::Quote(parse_blocks(&quoted)));
+        } else if let Some((start, _)) = list_marker(line) {
+            let ordered = start.is_some();
+            let mut items = vec![];
+            while i < lines.len() {
+                let offset = match list_marker(lines[i]) {
+                    Some((s, offset)) if s.is_some() == ordered => offset,
+                    _ => break,
+                };
+                let mut item = vec![&lines[i][offset..]];
+                i += 1;
+                while i < lines.len() {
+                    let line = lines[i];
+                    let blank = line.trim().is_empty();
+                    let previous_blank = item.last().is_none_or(|l| l.trim().is_empty());
+                    if blank {
+                        item.push("");
+                    } else if indent(line) >= offset {
+                        item.push(&line[offset..]);
+                    } else if list_marker(line).is_some() {
+                        // Next item
+                        break;
+                    } else if !previous_blank && !interrupts_paragraph(line) {
+                        // Lazy continuation of the item paragraph
+                        item.push(line.trim_start());
+                    } else {
+                        break;
+                    }
+                    i += 1;
+                }
+                // A blank line followed by an unindented line ends the list
+                let ended = item.last().is_some_and(|l| l.is_empty())
+                    && lines.get(i).is_none_or(|l| list_marker(l).is_none());
+                items.push(parse_blocks(&item));
+                if ended {
+                    break;
+                }
+            }
+            blocks.push(Block::List { start, items });
+        } else {
+            let mut paragraph = vec![line.trim().to_string()];
+            i += 1;
+            while i < lines.len() && !lines[i].trim().is_empty() && !interrupts_paragraph(lines[i])
+            {
+                paragraph.push(lines[i].trim().to_string());
+                i += 1;
+            }
+            blocks.push(Block::Paragraph(paragraph));
+        }
+    }
+    blocks
+}
+
+// =============================================================================
+// Inlines
+// =============================================================================
+fn run_length(chars: &[char], i: usize) -> usize {
+    chars[i..].iter().take_while(|c| **c == chars[i]).count()
+}
+
+fn safe_link(url: &str) -> bool {
+    let url = url.to_lowercase();
+    LINK_SCHEMES.iter().any(|scheme| url.starts_with(scheme))
+}
+
+/// `<url>`, returning the URL and the index after the closing bracket.
+fn autolink(chars: &[char], i: usize) -> Option<(String, usize)> {
+    let end = i + chars[i..].iter().position(|c| *c == '>')?;
+    let url: String = chars[i + 1..end].iter().collect();
+    if safe_link(&url) && !url.contains(char::is_whitespace) {
+        Some((url, end + 1))
+    } else {
+        None
+    }
+}
+
+/// `[text](url)`, returning the text, the URL and the index after the closing parenthesis.
+fn link(chars: &[char], i: usize) -> Option<(String, String, usize)> {
+    let mut depth = 0;
+    let mut close = None;
+    for (j, c) in chars.iter().enumerate().skip(i) {
+        match c {
+            '[' => depth += 1,
+            ']' => {
+                depth -= 1;
+                if depth == 0 {
+                    close = Some(j);
+                    break;
+                }
+            }
+            _ => (),
+        }
+    }
+    let close = close?;
+    if chars.get(close + 1) != Some(&'(') {
+        return None;
+    }
+    let end = close + 1 + chars[close + 1..].iter().position(|c| *c == ')')?;
+    let target: String = chars[close + 2..end].iter().collect();
+    // Drop the optional title
+    let url = target.split_whitespace().next().unwrap_or("");
+    let url = url.trim_start_matches('<').trim_end_matches('>');
+    if !safe_link(url) {
+        return None;
+    }
+    let text = chars[i + 1..close].iter().collect();
+    Some((text, url.to_string(), end + 1))
+}
+
+/// Closing delimiter run of exactly `len` characters matching the opening run at `i`.
+fn closing_delimiter(chars: &[char], i: usize, len: usize) -> Option<usize> {
+    let c = chars[i];
+    let mut j = i + len;
+    while j < chars.len() {
+        if chars[j] == '`' {
+            // Do not look into code spans
+            let n = run_length(chars, j);
+            j += n;
+            while j < chars.len() && !(chars[j] == '`' && run_length(chars, j) == n) {
+                j += 1;
+            }
+            j += n;
+            continue;
+        }
+        if chars[j] != c {
+            j += 1;
+            continue;
+        }
+        let n = run_length(chars, j);
+        let after = chars.get(j + n);
+        let word_after = c == '_' && after.is_some_and(|a| a.is_alphanumeric());
+        if n == len && j > i + len && !chars[j - 1].is_whitespace() && !word_after {
+            return Some(j);
+        }
+        j += n;
+    }
+    None
+}
+
+fn inline(text: &str) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    let mut out = String::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        match c {
+            '\\' if chars.get(i + 1).is_some_and(char::is_ascii_punctuation) => {
+                out.push_str(&escape(&chars[i + 1].to_string()));
+                i += 2;
+            }
+            '\n' => {
+                out.push_str("<br>");
+                i += 1;
+            }
+            '`' => {
+                let n = run_length(&chars, i);
+                let close = (i + n..chars.len()).find(|j| {
+                    chars[*j] == '`' && run_length(&chars, *j) == n && chars[j - 1] != '`'
+                });
+                match close {
+                    Some(j) => {
+                        let code: String = chars[i + n..j].iter().collect();
+                        let code = code.replace('\n', " ");
+                        let code = match code.strip_prefix(' ').and_then(|c| c.strip_suffix(' ')) {
+                            Some(stripped) if !stripped.trim().is_empty() => stripped.to_string(),
+                            _ => code,
+                        };
+                        out.push_str(&format!("<code>{}</code>", escape(&code)));
+                        i = j + n;
+                    }
+                    None => {
+                        out.push_str(&"`".repeat(n));
+                        i += n;
+                    }
+                }
+            }
+            '<' => match autolink(&chars, i) {
+                Some((url, end)) => {
+                    out.push_str(&format!("<a href=\"{0}\">{0}</a>", escape(&url)));
+                    i = end;
+                }
+                None => {
+                    out.push_str("&lt;");
+                    i += 1;
+                }
+            },
+            '[' => match link(&chars, i) {
+                Some((text, url, end)) => {
+                    out.push_str(&format!(
+                        "<a href=\"{}\">{}</a>",
+                        escape(&url),
+                        inline(&text)
+                    ));
+                    i = end;
+                }
+                None => {
+                    out.push('[');
+                    i += 1;
+                }
+            },
+            '*' | '_' | '~' => {
+                let n = run_length(&chars, i);
+                let len = if c == '~' { 2 } else { usize::min(n, 3) };
+                let opens = n >= len
+                    && chars.get(i + n).is_some_and(|next| !next.is_whitespace())
+                    && !(c == '_' && i > 0 && chars[i - 1].is_alphanumeric());
+                let close = if opens && n == len {
+                    closing_delimiter(&chars, i, len)
+                } else {
+                    None
+                };
+                match close {
+                    Some(j) => {
+                        let inner: String = chars[i + len..j].iter().collect();
+                        let (open, close) = match (c, len) {
+                            ('~', _) => ("<del>", "</del>"),
+                            (_, 1) => ("<em>", "</em>"),
+                            (_, 2) => ("<strong>", "</strong>"),
+                            _ => ("<strong><em>", "</em></strong>"),
+                        };
+                        out.push_str(&format!("{}{}{}", open, inline(&inner), close));
+                        i = j + len;
+                    }
+                    None => {
+                        out.push_str(&chars[i..i + n].iter().collect::<String>());
+                        i += n;
+                    }
+                }
+            }
+            c => {
+                out.push_str(&escape(&c.to_string()));
+                i += 1;
+            }
+        }
+    }
+    out
+}
+
+// =============================================================================
+// HTML
+// =============================================================================
+fn render(blocks: &[Block], out: &mut String) {
+    for block in blocks {
+        match block {
+            Block::Paragraph(lines) => {
+                out.push_str(&format!("<p>{}</p>", inline(&lines.join("\n"))))
+            }
+            Block::Heading(level, title) => {
+                out.push_str(&format!("<h{0}>{1}</h{0}>", level, inline(title)))
+            }
+            Block::Code(lang, lines) => {
+                match lang {
+                    Some(lang) => {
+                        out.push_str(&format!("<pre><code class=\"language-{}\">", escape(lang)))
+                    }
+                    None => out.push_str("<pre><code>"),
+                }
+                for line in lines {
+                    out.push_str(&escape(line));
+                    out.push('\n');
+                }
+                out.push_str("</code></pre>");
+            }
+            Block::Quote(blocks) => {
+                out.push_str("<blockquote>");
+                render(blocks, out);
+                out.push_str("</blockquote>");
+            }
+            Block::List { start, items } => {
+                let tag = match start {
+                    Some(1) => {
+                        out.push_str("<ol>");
+                        "ol"
+                    }
+                    Some(n) => {
+                        out.push_str(&format!("<ol start=\"{}\">", n));
+                        "ol"
+                    }
+                    None => {
+                        out.push_str("<ul>");
+                        "ul"
+                    }
+                };
+                for item in items {
+                    out.push_str("<li>");
+                    // Keep the first paragraph of items tight
+                    match item.split_first() {
+                        Some((Block::Paragraph(lines), rest)) => {
+                            out.push_str(&inline(&lines.join("\n")));
+                            render(rest, out);
+                        }
+                        _ => render(item, out),
+                    }
+                    out.push_str("</li>");
+                }
+                out.push_str(&format!("</{}>", tag));
+            }
+            Block::Rule => out.push_str("<hr>"),
+        }
+    }
+}
+
+/// HTML version of a message typed in CommonMark, or `None` when it has no formatting.
+pub fn to_html(text: &str) -> Option<String> {
+    let lines: Vec<&str> = text.lines().collect();
+    let blocks = parse_blocks(&lines);
+    let html = match blocks.as_slice() {
+        // Do not wrap single paragraph messages
+        [Block::Paragraph(lines)] => inline(&lines.join("\n")),
+        blocks => {
+            let mut html = String::new();
+            render(blocks, &mut html);
+            html
+        }
+    };
+    if html == escape(text.trim()).replace('\n', "<br>") {
+        None
+    } else {
+        Some(html)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::to_html;
+
+    #[test]
+    fn plain_text_has_no_html() {
+        assert_eq!(to_html("hello world"), None);
+        assert_eq!(to_html("a < b"), None);
+    }
+
+    #[test]
+    fn list_continuation_with_unicode_indent() {
+        // Ideographic spaces are not indentation, the line is a lazy continuation
+        assert_eq!(
+            to_html("- a\n\u{3000}b").as_deref(),
+            Some("<ul><li>a<br>b</li></ul>")
+        );
+        assert_eq!(
+            to_html("1. a\n   \u{3000}b").as_deref(),
+            Some("<ol><li>a<br>b</li></ol>")
+        );
+    }
+
+    #[test]
+    fn nested_lists() {
+        assert_eq!(
+            to_html("- a\n  - b\n  - c\n- d").as_deref(),
+            Some("<ul><li>a<ul><li>b</li><li>c</li></ul></li><li>d</li></ul>")
+        );
+        assert_eq!(
+            to_html("3. a\n4. b").as_deref(),
+            Some("<ol start=\"3\"><li>a</li><li>b</li></ol>")
+        );
+    }
+
+    #[test]
+    fn emphasis() {
+        assert_eq!(to_html("*a*").as_deref(), Some("<em>a</em>"));
+        assert_eq!(to_html("**a**").as_deref(), Some("<strong>a</strong>"));
+        assert_eq!(
+            to_html("***a***").as_deref(),
+            Some("<strong><em>a</em></strong>")
+        );
+        assert_eq!(to_html("~~a~~").as_deref(), Some("<del>a</del>"));
+        // Delimiters followed by a space or inside words do not open emphasis
+        assert_eq!(to_html("a * b * c"), None);
+        assert_eq!(to_html("snake_case_name"), None);
+        // Unclosed delimiters are kept
+        assert_eq!(to_html("*a"), None);
+        // Code spans hide delimiters
+        assert_eq!(
+            to_html("*a `*` b*").as_deref(),
+            Some("<em>a <code>*</code> b</em>")
+        );
+    }
+
+    #[test]
+    fn html_is_escaped() {
+        assert_eq!(
+            to_html("**<script>**").as_deref(),
+            Some("<strong>&lt;script&gt;</strong>")
+        );
+    }
+
+    #[test]
+    fn links() {
+        assert_eq!(
+            to_html("[a](https://example.org)").as_deref(),
+            Some("<a href=\"https://example.org\">a</a>")
+        );
+        assert_eq!(
+            to_html("<https://example.org>").as_deref(),
+            Some("<a href=\"https://example.org\">https://example.org</a>")
+        );
+        // Unsafe schemes are not linked
+        assert_eq!(to_html("[a](javascript:alert(1))"), None);
+    }
+
+    #[test]
+    fn code_blocks() {
+        assert_eq!(
+            to_html("```rust\nlet a = 1 < 2;\n```").as_deref(),
+            Some("<pre><code class=\"language-rust\">let a = 1 &lt; 2;\n</code></pre>")
+        );
+    }
+}
@@ -1,2 +1,6 @@
 pub mod editable_text;
+pub mod emoji;
+pub mod html;
 pub mod line;
+pub mod markdown;
+pub mod styled_text;
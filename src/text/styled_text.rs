@@ -0,0 +1,140 @@
+use super::editable_text::StringBlockItem;
+use super::line::char_width;
+use tui::style::{Color, Style};
+
+/// Piece of text drawn with a single style.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Span {
+    pub s: String,
+    pub style: Style,
+}
+
+impl Span {
+    pub fn new(s: &str, style: Style) -> Self {
+        Self {
+            s: s.to_string(),
+            style,
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.s.chars().fold(0, |acc, c| acc + char_width(c))
+    }
+}
+
+/// Read only text made of lines of styled spans.
+///
+/// Lines are wrapped on the characters, like the lines of an `EditableText`.
+#[derive(Debug, Clone, Default)]
+pub struct StyledText {
+    pub lines: Vec<Vec<Span>>,
+}
+
+/// Apply `style` over `base`, keeping the colors of `base` that `style` leaves unset.
+pub fn patch(base: Style, style: Style) -> Style {
+    Style {
+        fg: if style.fg == Color::Reset {
+            base.fg
+        } else {
+            style.fg
+        },
+        bg: if style.bg == Color::Reset {
+            base.bg
+        } else {
+            style.bg
+        },
+        modifier: base.modifier | style.modifier,
+    }
+}
+
+impl StyledText {
+    pub fn new(text: &str) -> Self {
+        Self {
+            lines: text
+                .split('\n')
+                .map(|l| vec![Span::new(l, Style::default())])
+                .collect(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lines
+            .iter()
+            .all(|l| l.iter().all(|span| span.s.trim().is_empty()))
+    }
+
+    /// Insert a span at the start of the first line.
+    pub fn prepend(&mut self, span: Span) {
+        match self.lines.first_mut() {
+            Some(line) => line.insert(0, span),
+            None => self.lines.push(vec![span]),
+        }
+    }
+
+    fn line_width(line: &[Span]) -> usize {
+        line.iter().map(Span::width).sum()
+    }
+
+    pub fn height(&self, width: u16) -> usize {
+        self.lines
+            .iter()
+            .map(|l| Self::line_width(l).div_ceil(width as usize))
+            .map(|h| usize::max(h, 1))
+            .sum()
+    }
+
+    /// Split a line in graphic lines of at most `width` columns.
+    fn wrap(line: &[Span], width: u16) -> Vec<Vec<(u16, Span)>> {
+        let mut glines = vec![];
+        let mut gline = vec![];
+        let mut gline_w = 0;
+        for span in line {
+            let mut chunk = String::new();
+            let mut chunk_x = gline_w;
+            for c in span.s.chars() {
+                let c_w = char_width(c);
+                // If we overflow the line, continue on the next one
+                if gline_w + c_w > width as usize {
+                    if !chunk.is_empty() {
+                        gline.push((chunk_x as u16, Span::new(&chunk, span.style)));
+                        chunk.clear();
+                    }
+                    glines.push(std::mem::take(&mut gline));
+                    gline_w = 0;
+                    chunk_x = 0;
+                }
+                chunk.push(c);
+                gline_w += c_w;
+            }
+            if !chunk.is_empty() {
+                gline.push((chunk_x as u16, Span::new(&chunk, span.style)));
+            }
+        }
+        if !gline.is_empty() || glines.is_empty() {
+            glines.push(gline);
+        }
+        glines
+    }
+
+    /// Block items of the graphic lines displayed in `area`, starting from graphic line `gline_i`.
+    pub fn to_block(&self, area: tui::layout::Rect, gline_i: usize) -> Vec<StringBlockItem> {
+        let mut ret = vec![];
+        let glines = self
+            .lines
+            .iter()
+            .flat_map(|line| Self::wrap(line, area.width))
+            .skip(gline_i)
+            .take(area.height as usize);
+        for (y, gline) in glines.enumerate() {
+            for (x, span) in gline {
+                ret.push(StringBlockItem {
+                    x: area.x + x,
+                    y: area.y + y as u16,
+                    s: span.s,
+                    style: span.style,
+                });
+            }
+        }
+        ret
+    }
+}
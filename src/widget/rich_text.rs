@@ -0,0 +1,54 @@
+use crate::text::{
+    editable_text::StringBlockItem,
+    styled_text::{self, StyledText},
+};
+use crate::widget::{
+    scroll::{Element, PartialWidget},
+    Height,
+};
+use tui::style::Style;
+
+/// Read only text drawn with the styles of its spans.
+#[derive(Debug)]
+pub struct RichText {
+    pub text: StyledText,
+    // Style the spans are drawn over
+    pub style: Style,
+}
+
+impl RichText {
+    pub fn new(text: StyledText) -> Self {
+        Self {
+            text,
+            style: Style::default(),
+        }
+    }
+}
+
+impl tui::widgets::Widget for RichText {
+    fn draw(&mut self, area: tui::layout::Rect, buf: &mut tui::buffer::Buffer) {
+        self.partial_draw(0, area, buf);
+    }
+}
+
+impl Height for RichText {
+    fn height(&self, width: u16) -> usize {
+        self.text.height(width)
+    }
+}
+
+impl PartialWidget for RichText {
+    fn partial_draw(
+        &mut self,
+        y_offset: usize,
+        area: tui::layout::Rect,
+        buf: &mut tui::buffer::Buffer,
+    ) {
+        for StringBlockItem { x, y, s, style } in self.text.to_block(area, y_offset) {
+            let width = (area.x + area.width - x) as usize;
+            buf.set_stringn(x, y, s, width, styled_text::patch(self.style, style));
+        }
+    }
+}
+
+impl Element for RichText {}
@@ -1,4 +1,6 @@
+use crate::text::styled_text::StyledText;
 use crate::widget::{
+    rich_text::RichText,
     scroll::{Element, PartialWidget},
     text::Text,
     Height,
@@ -36,20 +38,29 @@ pub struct RoomEntry {
     pub conf: Conf,
     pub meta: Meta,
     pub meta_widget: Text,
-    pub content_widget: Text,
+    pub content_widget: RichText,
 }
 
 impl RoomEntry {
     pub fn new(meta: Meta, content: &str, conf: Conf) -> Self {
+        Self::styled(meta, StyledText::new(content), conf)
+    }
+
+    pub fn styled(meta: Meta, content: StyledText, conf: Conf) -> Self {
         let mut meta_widget = Text::new(&meta.to_string());
         meta_widget.one_line = true;
         Self {
             conf,
             meta,
             meta_widget,
-            content_widget: Text::new(content),
+            content_widget: RichText::new(content),
         }
     }
+
+    pub fn with_style(mut self, style: Style) -> Self {
+        self.content_widget.style = style;
+        self
+    }
 }
 
 impl tui::widgets::Widget for RoomEntry {
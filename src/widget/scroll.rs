@@ -1,4 +1,5 @@
 use super::Height;
+use tui::style::Modifier;
 use tui::widgets::Widget;
 
 pub trait PartialWidget {
@@ -22,6 +23,8 @@ pub struct Scroll {
     cursor: Cursor,
     widgets: Vec<Box<dyn Element>>,
     next_move: isize,
+    // Highlighted element, kept in view
+    selected: Option<usize>,
 }
 
 impl Scroll {
@@ -30,6 +33,7 @@ impl Scroll {
             cursor: Cursor { widget: 0, y: 0 },
             widgets,
             next_move: 0,
+            selected: None,
         }
     }
 
@@ -37,6 +41,91 @@ impl Scroll {
         self.widgets.push(element)
     }
 
+    pub fn insert(&mut self, index: usize, element: Box<dyn Element>) {
+        // Keep the view on the same content
+        if index <= self.cursor.widget && !self.widgets.is_empty() {
+            self.cursor.widget += 1;
+        }
+        match self.selected.as_mut() {
+            Some(selected) if index <= *selected => *selected += 1,
+            _ => (),
+        }
+        self.widgets.insert(index, element)
+    }
+
+    pub fn remove(&mut self, index: usize) {
+        // Keep the view on the same content
+        if index < self.cursor.widget {
+            self.cursor.widget -= 1;
+        } else if index == self.cursor.widget {
+            self.cursor.y = 0;
+        }
+        self.selected = match self.selected {
+            Some(selected) if index < selected => Some(selected - 1),
+            Some(selected) if index == selected => None,
+            selected => selected,
+        };
+        self.widgets.remove(index);
+        if self.cursor.widget >= self.widgets.len() {
+            self.cursor.widget = self.widgets.len().saturating_sub(1);
+        }
+    }
+
+    pub fn replace(&mut self, index: usize, element: Box<dyn Element>) {
+        // The new element height may differ
+        if index == self.cursor.widget {
+            self.cursor.y = 0;
+        }
+        self.widgets[index] = element;
+    }
+
+    pub fn len(&self) -> usize {
+        self.widgets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.widgets.is_empty()
+    }
+
+    pub fn select(&mut self, index: Option<usize>) {
+        self.selected = index.filter(|i| *i < self.widgets.len());
+    }
+
+    pub fn selected(&self) -> Option<usize> {
+        self.selected
+    }
+
+    /// Move the view so that the selected element is visible.
+    fn show_selected(&mut self, area: tui::layout::Rect) {
+        let selected = match self.selected {
+            Some(selected) => selected,
+            None => return,
+        };
+        if selected < self.cursor.widget {
+            self.cursor = Cursor {
+                widget: selected,
+                y: 0,
+            };
+            return;
+        }
+        // Scroll down until the selected element ends in the view, or reaches its top
+        loop {
+            let bottom: usize = self.widgets[self.cursor.widget..=selected]
+                .iter()
+                .map(|w| w.height(area.width))
+                .sum::<usize>()
+                - self.cursor.y;
+            if bottom <= area.height as usize || self.cursor.widget == selected {
+                break;
+            }
+            self._down(area.width);
+        }
+    }
+
+    pub fn is_at_top(&self) -> bool {
+        self.cursor.widget == 0 && self.cursor.y == 0
+    }
+
     fn _up(&mut self, width: u16) {
         if self.cursor.y > 0 {
             self.cursor.y -= 1;
@@ -99,17 +188,19 @@ impl Widget for Scroll {
             }
             std::cmp::Ordering::Equal => (),
         }
+        self.show_selected(area);
 
         // Draw
         let mut height = 0;
         for (i, widget) in self.widgets.iter_mut().skip(self.cursor.widget).enumerate() {
             let w_h = widget.height(area.width);
 
-            if i == 0 {
+            let widget_area = if i == 0 {
                 let mut area = area;
                 area.height = usize::min(w_h - self.cursor.y, area.height as usize) as u16;
                 widget.partial_draw(self.cursor.y, area, buf);
                 height += area.height;
+                area
             } else {
                 let mut area = area;
                 area.y += height;
@@ -118,6 +209,16 @@ impl Widget for Scroll {
                 height += area.height;
 
                 widget.draw(area, buf);
+                area
+            };
+
+            if self.selected == Some(self.cursor.widget + i) {
+                for y in widget_area.top()..widget_area.bottom() {
+                    for x in widget_area.left()..widget_area.right() {
+                        let cell = buf.get_mut(x, y);
+                        cell.set_modifier(cell.style.modifier | Modifier::REVERSED);
+                    }
+                }
             }
 
             if height >= area.height {